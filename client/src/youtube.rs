@@ -43,11 +43,15 @@ fn youtube_click(target: &Element, document: &Document) -> Result<(), JsValue> {
         let player = document.create_element("iframe")?;
         player.set_attribute("width", "100%")?;
         player.set_attribute("height", "100%")?;
+        let start = target
+            .get_attribute("data-start")
+            .map(|start| format!("&start={}", start))
+            .unwrap_or_default();
         player.set_attribute(
             "src",
             &format!(
-                "https://www.youtube.com/embed/{}?autoplay=1&rel=0",
-                youtube_id
+                "https://www.youtube.com/embed/{}?autoplay=1&rel=0{}",
+                youtube_id, start
             ),
         )?;
         player.set_attribute("allowfullscreen", "")?;
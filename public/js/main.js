@@ -1,5 +1,6 @@
-import init from '/js/nickmass_com_client.js';
+const script = document.querySelector('script[data-client]');
 async function run() {
-  await init('/js/nickmass_com_client_bg.wasm');
+  const { default: init } = await import(script.dataset.client);
+  await init(script.dataset.wasm);
 }
 run();
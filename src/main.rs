@@ -1,9 +1,11 @@
 use tokio::runtime::Runtime;
 use tracing::metadata::LevelFilter;
+use tracing_subscriber::filter::{EnvFilter, Targets};
 use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Layer, Registry};
 
 mod server;
-use server::Config;
+use server::{telemetry, Config, LogFormat};
 
 fn main() {
     let config = Config::load();
@@ -14,9 +16,50 @@ fn main() {
     rt.block_on(run(config));
 }
 
+type LogFilter = Box<dyn Layer<Registry> + Send + Sync>;
+
 async fn run(config: Config) {
-    let log_filter = tracing_subscriber::filter::Targets::new().with_default(LevelFilter::OFF);
-    let log_filter = match (config.verbosity, config.silent) {
+    let (log_filter, log_filter_handle) = reload::Layer::new(build_log_filter(&config));
+
+    let fmt_layer = match config.log_format {
+        LogFormat::Full => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer().compact().boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt_layer)
+        .with(telemetry::layer(&config))
+        .init();
+
+    let reload_logging = move |config: &Config| {
+        if let Err(e) = log_filter_handle.reload(build_log_filter(config)) {
+            tracing::warn!("unable to reload log filter: {}", e);
+        }
+    };
+
+    server::run(config, reload_logging).await;
+    telemetry::shutdown();
+}
+
+/// The log_filter directive when set, it has already been checked when the
+/// config was loaded, otherwise the -v and -s flags
+fn build_log_filter(config: &Config) -> LogFilter {
+    match config.log_filter.as_deref().map(EnvFilter::try_new) {
+        Some(Ok(filter)) => filter.boxed(),
+        _ => verbosity_filter(config.verbosity, config.silent).boxed(),
+    }
+}
+
+fn verbosity_filter(verbosity: u8, silent: bool) -> Targets {
+    let log_filter = Targets::new().with_default(LevelFilter::OFF);
+    match (verbosity, silent) {
         (1, true) => log_filter
             .with_target("nickmass_com", LevelFilter::ERROR)
             .with_target("tower_http", LevelFilter::ERROR),
@@ -37,12 +80,5 @@ async fn run(config: Config) {
             .with_target("tower_http", LevelFilter::TRACE)
             .with_default(LevelFilter::INFO),
         _ => log_filter.with_default(LevelFilter::TRACE),
-    };
-
-    tracing_subscriber::registry()
-        .with(log_filter)
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    server::run(config).await
+    }
 }
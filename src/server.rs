@@ -1,77 +1,164 @@
 use axum::body::Body;
 use axum::extract::{ConnectInfo, Extension, Path, Query, State};
+use axum::handler::Handler;
 use axum::http::request::Parts;
 use axum::http::{header, HeaderValue, Request, StatusCode};
 use axum::middleware::Next;
 use axum::response::{Html, IntoResponse, IntoResponseParts, Redirect};
-use axum::routing::{get, get_service};
+use axum::routing::{get, post};
 use axum::{async_trait, Json, RequestPartsExt, Router};
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::{headers, TypedHeader};
+use serde::Serialize;
 use tower::ServiceBuilder;
 use tower_http::classify::ServerErrorsFailureClass;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
 use tower_http::trace::{MakeSpan, OnFailure, OnRequest, OnResponse};
+use tracing::Instrument;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+mod assets;
 mod auth;
+mod caching;
 mod config;
+mod conn;
+mod csp;
 mod db;
 mod error;
+mod forwarded;
+mod listener;
+mod markdown;
+mod metrics;
 mod models;
 mod posts;
+mod rate_limit;
+mod reload;
+mod request_id;
+mod security;
 mod sessions;
+mod shortcodes;
+pub mod telemetry;
+mod tls;
 mod users;
 mod views;
 
-pub use config::Config;
+pub use config::{Config, LogFormat};
 
 use auth::Authenticated;
+use caching::{CachePolicy, Cached, Conditional, Validators};
+use conn::Lifecycle;
 use db::Db;
 use error::{Error, JsonError};
+use forwarded::{ClientIp, ForwardedProto};
+use markdown::Renderer;
 use posts::{Post, PostClient, PostPage};
+use rate_limit::RateLimiter;
+use reload::{Reloadable, Reloader};
 use sessions::{Session, SessionStore};
 use users::{User, UserClient};
 
-const CSP_DIRECTIVE: &'static str = "default-src 'none'; connect-src 'self'; font-src 'self'; frame-src https://www.youtube.com; img-src 'self' https://img.youtube.com; media-src 'self'; script-src 'self' 'wasm-unsafe-eval'; style-src 'self'; frame-ancestors 'none'; base-uri 'none'; form-action 'self';";
+const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+const CSP_REPORT_LIMIT: usize = 16 * 1024;
 
 #[derive(axum::extract::FromRef, Clone)]
 struct ServerState {
-    config: Arc<Config>,
+    #[from_ref(skip)]
+    config: Reloadable<Config>,
     db: Db,
     session: Arc<Session>,
+    renderer: Arc<Renderer>,
+    rate_limiter: Arc<RateLimiter>,
+    lifecycle: Lifecycle,
 }
 
-pub async fn run(config: Config) {
+/// Handlers see the config as it was when their request arrived, a reload
+/// part way through does not change it under them
+impl axum::extract::FromRef<ServerState> for Arc<Config> {
+    fn from_ref(state: &ServerState) -> Arc<Config> {
+        state.config.current()
+    }
+}
+
+/// Runs the server until a shutdown signal, `reload_logging` is called with
+/// the new config whenever it is reloaded
+pub async fn run(config: Config, reload_logging: impl Fn(&Config) + Send + Sync + 'static) {
     let config = Arc::new(config);
     let db = Db::new(config.redis_url.to_string()).unwrap();
     let session = Arc::new(Session::new(config.session_key.as_slice()));
+    let renderer = Arc::new(Renderer::new(
+        config.raw_html_roles.clone(),
+        config.render_math,
+        config.render_cache_size,
+        config.render_cache_redis.then(|| db.clone()),
+    ));
+
+    let rate_limiter = Arc::new(RateLimiter::new(&config, db.clone()));
+    let lifecycle = Lifecycle::start();
 
     let state = ServerState {
-        config: config.clone(),
+        config: Reloadable::new(Config::clone(&config)),
         db,
         session,
+        renderer,
+        rate_limiter: rate_limiter.clone(),
+        lifecycle: lifecycle.clone(),
     };
 
-    let html_layers = ServiceBuilder::new().layer(
-        tower_http::set_header::SetResponseHeaderLayer::<_>::if_not_present(
-            header::CONTENT_SECURITY_POLICY,
-            HeaderValue::from_static(CSP_DIRECTIVE),
-        ),
-    );
+    let csp_policy = Reloadable::new(csp::Policy::new(&config));
+    let html_layers = axum::middleware::from_fn_with_state(csp_policy.clone(), csp::apply);
+
+    let security_headers = Reloadable::new(security::SecurityHeaders::new(&config));
+    let security_layer = |group| {
+        axum::middleware::from_fn_with_state((security_headers.clone(), group), security::apply)
+    };
+
+    let compression = config.compression;
+    let compression_layers = ServiceBuilder::new()
+        .layer(tower_http::set_header::SetResponseHeaderLayer::appending(
+            header::VARY,
+            move |_: &http::Response<_>| {
+                compression.then(|| HeaderValue::from_static("accept-encoding"))
+            },
+        ))
+        .layer(
+            tower_http::compression::CompressionLayer::new()
+                .compress_when(DefaultPredicate::new().and(compress_dynamic(compression))),
+        );
 
     tracing::info!("serving assets from: {}", config.asset_dir);
-    let public_static = get_service(
-        tower_http::services::ServeDir::new(config.asset_dir.as_str())
-            .append_index_html_on_directories(false),
-    );
+    match assets::AssetManifest::build(config.asset_dir.as_str()) {
+        Ok(manifest) => {
+            tracing::info!("fingerprinted {} assets", manifest.len());
+            assets::install(manifest);
+        }
+        Err(err) => tracing::warn!("unable to fingerprint assets: {}", err),
+    }
 
+    let serve_dir = Reloadable::new(assets::serve_dir(&config));
     let static_files = Router::new()
-        .route("/css/*path", public_static.clone())
-        .route("/fonts/*path", public_static.clone())
-        .route("/img/*path", public_static.clone())
-        .route("/js/*path", public_static.clone());
+        .route("/css/*path", get(assets::serve))
+        .route("/fonts/*path", get(assets::serve))
+        .route("/img/*path", get(assets::serve))
+        .route("/js/*path", get(assets::serve))
+        .layer(axum::middleware::from_fn(assets::serve_fingerprinted))
+        .layer(security_layer(security::Group::Static))
+        .with_state(serve_dir.clone());
+
+    // Probes sit outside the session layer so frequent health checks never
+    // create sessions
+    let probes = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+        .route(csp::REPORT_PATH, post(csp::report))
+        .layer(axum::extract::DefaultBodyLimit::max(CSP_REPORT_LIMIT));
+    let probes = if config.metrics_port.is_none() {
+        probes.route("/metrics", get(metrics_endpoint))
+    } else {
+        probes
+    };
 
     let api = Router::new()
         .route("/users/current", get(api_user))
@@ -83,7 +170,8 @@ pub async fn run(config: Config) {
                 .delete(api_posts_delete),
         )
         .with_session_layer::<JsonError>(state.clone())
-        .fallback(api_fallback);
+        .fallback(api_fallback)
+        .layer(security_layer(security::Group::Api));
 
     let auth = Router::new()
         .route("/logout", get(auth_logout))
@@ -96,7 +184,8 @@ pub async fn run(config: Config) {
         .route("/post/:post", get(view_post))
         .nest("/auth", auth)
         .with_session_layer::<HtmlError>(state.clone())
-        .layer(html_layers)
+        .layer(html_layers.clone())
+        .layer(security_layer(security::Group::Html))
         .nest("/api", api)
         .layer(
             tower_http::trace::TraceLayer::new_for_http()
@@ -106,73 +195,103 @@ pub async fn run(config: Config) {
                 .on_failure(MassTraceLog),
         )
         .merge(static_files)
-        .fallback(view_fallback)
+        .merge(probes)
+        .fallback(
+            view_fallback
+                .layer(html_layers)
+                .layer(security_layer(security::Group::Html)),
+        )
+        .layer(axum::middleware::from_fn(metrics::track_requests))
+        .layer(compression_layers)
+        .layer(axum::middleware::from_fn_with_state(
+            config.clone(),
+            forwarded::resolve,
+        ))
+        .layer(axum::middleware::from_fn(request_id::propagate))
         .with_state(state.clone());
 
-    tracing::info!(
-        "starting server on: {}:{}",
-        config.listen_ip,
-        config.listen_port
-    );
+    let reloader = Reloader {
+        config: state.config.clone(),
+        csp: csp_policy,
+        security_headers,
+        rate_limiter,
+        static_files: serve_dir,
+        logging: Box::new(reload_logging),
+    };
+    tokio::spawn(reloader.watch());
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let resolver =
+                Arc::new(tls::CertResolver::load(cert, key).expect("Valid tls certificate"));
+            tokio::spawn(resolver.clone().watch());
+            Some(tls::acceptor(resolver))
+        }
+        _ => None,
+    };
 
-    let listener = tokio::net::TcpListener::bind(&(config.listen_ip, config.listen_port))
+    let listeners = listener::Listener::bind(&config.listen)
         .await
-        .unwrap();
+        .expect("Able to listen on every address");
+
+    let settings = conn::ConnSettings::new(&config);
+    let mut servers = Vec::new();
+    for listener in listeners {
+        tracing::info!("starting server on: {}", listener);
+        servers.push(conn::serve(
+            listener,
+            app.clone(),
+            tls.clone(),
+            settings.clone(),
+            lifecycle.clone(),
+        ));
+    }
 
-    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Some(redirect_port) = config.redirect_port {
+        tracing::info!(
+            "redirecting http to https on: {}:{}",
+            config.listen_ip,
+            redirect_port
+        );
 
-    let (close_tx, close_rx) = tokio::sync::watch::channel(());
+        let listener = tokio::net::TcpListener::bind(&(config.listen_ip, redirect_port))
+            .await
+            .map(listener::Listener::Tcp)
+            .unwrap();
+        let redirect = Router::new()
+            .fallback(redirect_https)
+            .with_state(config.clone());
+
+        servers.push(conn::serve(
+            listener,
+            redirect,
+            None,
+            settings.clone(),
+            lifecycle.clone(),
+        ));
+    }
 
-    loop {
-        let (socket, remote_addr) = tokio::select! {
-            _ = shutdown() => break,
-            conn = listener.accept() => conn.unwrap(),
-        };
+    if let Some(metrics_port) = config.metrics_port {
+        tracing::info!("serving metrics on: {}:{}", config.listen_ip, metrics_port);
 
-        use tower::Service;
-        let tower_service = make_service.call(remote_addr).await.unwrap();
-
-        let close_rx = close_rx.clone();
-
-        tokio::spawn(async move {
-            let socket = hyper_util::rt::TokioIo::new(socket);
-
-            let hyper_service =
-                hyper::service::service_fn(move |request: Request<hyper::body::Incoming>| {
-                    tower_service.clone().call(request)
-                });
-
-            let conn = hyper::server::conn::http1::Builder::new()
-                .serve_connection(socket, hyper_service)
-                .with_upgrades();
-
-            let mut conn = std::pin::pin!(conn);
-
-            loop {
-                tokio::select! {
-                    result = conn.as_mut() => {
-                        if let Err(err) = result {
-                            tracing::error!("failed to serve connection: {err:#}");
-                        }
-                        break;
-                    },
-                    _ = shutdown() => {
-                        conn.as_mut().graceful_shutdown();
-                    }
-                }
-            }
-
-            drop(close_rx);
-        });
+        let listener = tokio::net::TcpListener::bind(&(config.listen_ip, metrics_port))
+            .await
+            .map(listener::Listener::Tcp)
+            .unwrap();
+        let metrics = Router::new()
+            .route("/metrics", get(metrics_endpoint))
+            .with_state(state);
+
+        servers.push(conn::serve(
+            listener,
+            metrics,
+            None,
+            settings.clone(),
+            lifecycle.clone(),
+        ));
     }
 
-    drop(close_rx);
-    drop(listener);
-    tracing::info!(
-        "signal received shutting down, waiting for {} tasks to complete",
-        close_tx.receiver_count()
-    );
-    close_tx.closed().await;
+    futures::future::join_all(servers).await;
 }
 
 async fn shutdown() {
@@ -187,32 +306,63 @@ async fn shutdown() {
     }
 }
 
+/// Static files are compressed ahead of time, only rendered pages and api
+/// responses are worth compressing as they are sent
+fn compress_dynamic(
+    enabled: bool,
+) -> impl Fn(StatusCode, http::Version, &http::HeaderMap, &http::Extensions) -> bool + Clone {
+    move |_, _, headers, _| {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+
+        enabled
+            && (content_type.starts_with("text/html")
+                || content_type.starts_with("application/json"))
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct MassTraceLog;
 
 impl<B> MakeSpan<B> for MassTraceLog {
     fn make_span(&mut self, request: &Request<B>) -> tracing::Span {
-        let id = uuid::Uuid::new_v4();
-        if let Some(ConnectInfo(conn)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let id = request
+            .extensions()
+            .get::<request_id::RequestId>()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        let span = if let Some(ConnectInfo(conn)) =
+            request.extensions().get::<ConnectInfo<SocketAddr>>()
+        {
             tracing::span!(
-                tracing::Level::DEBUG,
+                tracing::Level::INFO,
                 "request",
                 method = %request.method(),
                 uri = %request.uri(),
                 remote_addr = %conn,
+                client_ip = tracing::field::Empty,
                 request_id = %id,
                 status_code = tracing::field::Empty
             )
         } else {
             tracing::span!(
-                tracing::Level::DEBUG,
+                tracing::Level::INFO,
                 "request",
                 method = %request.method(),
                 uri = %request.uri(),
                 request_id = %id,
                 status_code = tracing::field::Empty,
             )
+        };
+
+        if let Some(ClientIp(ip)) = request.extensions().get::<ClientIp>() {
+            span.record("client_ip", &tracing::field::display(ip));
         }
+
+        telemetry::set_parent(&span, request.headers());
+        span
     }
 }
 
@@ -310,75 +460,196 @@ impl IntoResponseParts for SessionStore {
 }
 
 async fn add_session<E: From<Error> + IntoResponse>(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
     db: State<Db>,
     session: State<Arc<Session>>,
     jar: axum_extra::extract::CookieJar,
     mut req: Request<Body>,
     next: Next,
 ) -> Result<impl IntoResponse, E> {
-    let sid = jar.get("sid").map(|c| c.value().to_string());
-
-    let ip = addr.ip();
-    let mut connection = db.get().await?;
-
-    let store = session.get_store(&mut connection, ip, sid).await;
-    req.extensions_mut().insert(store);
+    // Readers without a sid have no session to load, one is only started if
+    // the request writes to the store
+    let store = match jar.get("sid") {
+        Some(sid) => session.get_store(&db, ip, sid.value()).await?,
+        None => SessionStore::anonymous(),
+    };
+    req.extensions_mut().insert(store.clone());
 
     let mut res = next.run(req).await;
-    if let Some(_) = res.extensions_mut().remove::<SessionClear>() {
+    if res.extensions_mut().remove::<SessionClear>().is_some() {
         let cookie = Cookie::build(("sid", ""))
             .path("/")
             .http_only(true)
             .same_site(axum_extra::extract::cookie::SameSite::Lax);
         Ok((jar.remove(cookie), res).into_response())
-    } else if let Some(store) = res.extensions_mut().remove::<SessionStore>() {
-        let sid = store.sid();
-        session.set_store(&mut connection, store).await;
-        let cookie = Cookie::build(("sid", sid))
-            .path("/")
-            .http_only(true)
-            .max_age(time::Duration::days(30))
-            .same_site(axum_extra::extract::cookie::SameSite::Lax);
-        Ok((jar.add(cookie), res).into_response())
+    } else if let Some(store) = res
+        .extensions_mut()
+        .remove::<SessionStore>()
+        .filter(|store| store.is_dirty())
+    {
+        let mut connection = db.get().await?;
+        match session.set_store(&mut connection, ip, store).await {
+            Some(sid) => Ok((jar.add(session_cookie(sid)), res).into_response()),
+            None => Ok(res.into_response()),
+        }
+    } else if store.needs_refresh() {
+        // Sessions expire after being idle rather than after login, so one in
+        // use has its cookie and expiry pushed back now and then
+        let mut connection = db.get().await?;
+        match session.refresh(&mut connection, &store).await {
+            Some(sid) => Ok((jar.add(session_cookie(sid)), res).into_response()),
+            None => Ok(res.into_response()),
+        }
     } else {
         Ok(res.into_response())
     }
 }
 
+fn session_cookie(sid: String) -> Cookie<'static> {
+    Cookie::build(("sid", sid))
+        .path("/")
+        .http_only(true)
+        .max_age(time::Duration::days(30))
+        .same_site(axum_extra::extract::cookie::SameSite::Lax)
+        .build()
+}
+
 async fn view_index(
     State(db): State<Db>,
+    State(renderer): State<Arc<Renderer>>,
     user: Option<HtmlAuth>,
-) -> Result<Html<String>, HtmlError> {
+    conditional: Conditional,
+) -> Result<Cached<Html<String>>, HtmlError> {
     let user = user.map(|HtmlAuth(user)| user);
-    Ok(Html(views::index(user, db.get().await?, None).await?))
+    let index = views::index(user, db.get().await?, &renderer, &conditional, None).await?;
+    Ok(index.map(Html))
 }
 
 async fn view_page(
     State(db): State<Db>,
+    State(renderer): State<Arc<Renderer>>,
     user: Option<HtmlAuth>,
+    conditional: Conditional,
     Path(page): Path<i64>,
-) -> Result<Html<String>, HtmlError> {
+) -> Result<Cached<Html<String>>, HtmlError> {
     let user = user.map(|HtmlAuth(user)| user);
-    Ok(Html(views::index(user, db.get().await?, Some(page)).await?))
+    let index = views::index(user, db.get().await?, &renderer, &conditional, Some(page)).await?;
+    Ok(index.map(Html))
 }
 
 async fn view_post(
     State(db): State<Db>,
+    State(renderer): State<Arc<Renderer>>,
     user: Option<HtmlAuth>,
+    conditional: Conditional,
     Path(post): Path<String>,
-) -> Result<Html<String>, HtmlError> {
+) -> Result<Cached<Html<String>>, HtmlError> {
     let user = user.map(|HtmlAuth(user)| user);
 
     let db = db.get().await?;
 
-    let post = if let Some(post) = post.parse().ok() {
-        views::post_id(user, db, post).await?
+    let post = if let Ok(post) = post.parse() {
+        views::post_id(user, db, &renderer, &conditional, post).await?
     } else {
-        views::post_frag(user, db, post).await?
+        views::post_frag(user, db, &renderer, &conditional, post).await?
     };
 
-    Ok(Html(post))
+    Ok(post.map(Html))
+}
+
+async fn metrics_endpoint(State(db): State<Db>) -> impl IntoResponse {
+    db.record_pool_metrics();
+
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics::metrics().encode(),
+    )
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    draining: bool,
+    redis: &'static str,
+    templates: &'static str,
+}
+
+async fn readyz(
+    State(db): State<Db>,
+    State(lifecycle): State<Lifecycle>,
+) -> (StatusCode, Json<Readiness>) {
+    let draining = lifecycle.is_draining();
+
+    let redis = async {
+        let mut db = db.get().await?;
+        let _: String = redis::cmd("ping").query_async(&mut db).await?;
+        Ok::<_, Error>(())
+    };
+    let redis = tokio::time::timeout(READINESS_TIMEOUT, redis)
+        .await
+        .map_err(Error::from)
+        .and_then(|r| r);
+    let templates = views::not_found(None, None).map(|_| ());
+
+    let ready = !draining && redis.is_ok() && templates.is_ok();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    // The probe is public, the reason a check failed is only logged
+    let check = |name: &str, result: Result<(), Error>| match result {
+        Ok(()) => "ok",
+        Err(err) => {
+            tracing::warn!("readiness check {} failed: {}", name, err);
+            "error"
+        }
+    };
+
+    (
+        status,
+        Json(Readiness {
+            ready,
+            draining,
+            redis: check("redis", redis),
+            templates: check("templates", templates),
+        }),
+    )
+}
+
+#[derive(Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    git_hash: &'static str,
+    build_time: &'static str,
+}
+
+async fn version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("BUILD_GIT_HASH"),
+        build_time: env!("BUILD_TIME"),
+    })
+}
+
+/// Sends plain http visitors to the same path on the public base url, which
+/// may be served on a different port than the one listened on behind a proxy
+async fn redirect_https(State(config): State<Arc<Config>>, uri: http::Uri) -> Redirect {
+    let scheme = config.base_url.scheme_str().unwrap_or("https");
+    let host = config.base_url.host().unwrap_or("localhost");
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let location = match config.base_url.port_u16() {
+        Some(port) => format!("{}://{}:{}{}", scheme, host, port, path),
+        None => format!("{}://{}{}", scheme, host, path),
+    };
+
+    Redirect::permanent(&location)
 }
 
 async fn view_fallback() -> HtmlError {
@@ -389,35 +660,75 @@ async fn api_user(ApiAuth(user): ApiAuth) -> Json<User> {
     Json(user)
 }
 
-async fn api_posts_get_all(State(db): State<Db>) -> Result<Json<PostPage>, JsonError> {
+async fn api_posts_get_all(
+    State(db): State<Db>,
+    user: Option<ApiAuth>,
+    conditional: Conditional,
+) -> Result<Cached<Json<PostPage>>, JsonError> {
     let db = db.get().await?;
-    let client = PostClient::new(db);
+    let mut client = PostClient::new(db);
+    let revision = client.revision().await?;
     let posts = client.get_all(100, 0).await?;
 
-    Ok(Json(posts))
+    let validators = posts
+        .posts
+        .iter()
+        .fold(
+            Validators::builder("api::posts")
+                .add(revision.counter)
+                .modified(revision.modified),
+            views::add_post,
+        )
+        .build();
+    let policy = CachePolicy::for_user(user.as_ref());
+
+    if conditional.not_modified(&validators) {
+        Ok(Cached::not_modified(validators, policy))
+    } else {
+        Ok(Cached::fresh(validators, policy, Json(posts)))
+    }
 }
 
 async fn api_posts_get(
     State(db): State<Db>,
+    user: Option<ApiAuth>,
+    conditional: Conditional,
     Path(post): Path<String>,
-) -> Result<Json<Post>, JsonError> {
+) -> Result<Cached<Json<Post>>, JsonError> {
     let db = db.get().await?;
-    let client = PostClient::new(db);
+    let mut client = PostClient::new(db);
+    let revision = client.revision().await?;
 
-    let post = if let Some(post) = post.parse().ok() {
+    let post = if let Ok(post) = post.parse() {
         client.get(post).await?
     } else {
         client.get_by_fragment(post).await?
     };
 
-    Ok(Json(post))
+    let validators = views::add_post(
+        Validators::builder("api::post")
+            .add(revision.counter)
+            .modified(revision.modified),
+        &post,
+    )
+    .build();
+    let policy = CachePolicy::for_user(user.as_ref());
+
+    if conditional.not_modified(&validators) {
+        Ok(Cached::not_modified(validators, policy))
+    } else {
+        Ok(Cached::fresh(validators, policy, Json(post)))
+    }
 }
 
 async fn api_posts_post(
+    ClientIp(ip): ClientIp,
     State(db): State<Db>,
+    State(rate_limiter): State<Arc<RateLimiter>>,
     ApiAuth(user): ApiAuth,
     Json(post): Json<Post>,
 ) -> Result<Json<u64>, JsonError> {
+    limit_api_write(&rate_limiter, ip, &user).await?;
     let db = db.get().await?;
     let client = Authenticated::new(user, PostClient::new(db));
 
@@ -427,24 +738,32 @@ async fn api_posts_post(
 }
 
 async fn api_posts_put(
+    ClientIp(ip): ClientIp,
     State(db): State<Db>,
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    State(renderer): State<Arc<Renderer>>,
     ApiAuth(user): ApiAuth,
     Path(post_id): Path<u64>,
     Json(post): Json<Post>,
 ) -> Result<Json<u64>, JsonError> {
+    limit_api_write(&rate_limiter, ip, &user).await?;
     let db = db.get().await?;
     let client = Authenticated::new(user, PostClient::new(db));
 
     let id = client.update(post_id, post).await?;
+    renderer.invalidate(id).await;
 
     Ok(Json(id))
 }
 
 async fn api_posts_delete(
+    ClientIp(ip): ClientIp,
     State(db): State<Db>,
+    State(rate_limiter): State<Arc<RateLimiter>>,
     ApiAuth(user): ApiAuth,
     Path(post_id): Path<u64>,
 ) -> Result<Json<()>, JsonError> {
+    limit_api_write(&rate_limiter, ip, &user).await?;
     let db = db.get().await?;
     let client = Authenticated::new(user, PostClient::new(db));
 
@@ -453,33 +772,64 @@ async fn api_posts_delete(
     Ok(Json(()))
 }
 
+async fn limit_api_write(
+    rate_limiter: &RateLimiter,
+    ip: std::net::IpAddr,
+    user: &User,
+) -> Result<(), Error> {
+    use rate_limit::{Group, Key};
+
+    rate_limiter
+        .check(Group::ApiWrite, &[Key::Ip(ip), Key::User(user.id)])
+        .await
+}
+
 async fn api_fallback() -> JsonError {
     Error::NotFound.into()
 }
 
-async fn auth_logout(State(config): State<Arc<Config>>) -> impl IntoResponse {
+/// The configured base url, using the scheme the visitor actually connected
+/// with when a trusted proxy reports one
+fn base_url(config: &Config, ForwardedProto(proto): ForwardedProto) -> String {
+    match proto {
+        Some(proto) => {
+            let mut parts = config.base_url.clone().into_parts();
+            parts.scheme = Some(proto.scheme());
+            http::Uri::from_parts(parts)
+                .unwrap_or_else(|_| config.base_url.clone())
+                .to_string()
+        }
+        None => config.base_url.to_string(),
+    }
+}
+
+async fn auth_logout(
+    State(config): State<Arc<Config>>,
+    proto: ForwardedProto,
+) -> impl IntoResponse {
     let no_cache = headers::CacheControl::new().with_no_store();
 
     (
         SessionClear,
         TypedHeader(no_cache),
-        Redirect::temporary(&config.base_url.to_string()),
+        Redirect::temporary(&base_url(&config, proto)),
     )
 }
 
 async fn auth_google(
+    proto: ForwardedProto,
     State(config): State<Arc<Config>>,
     State(session): State<Arc<Session>>,
     store: SessionStore,
 ) -> Result<impl IntoResponse, HtmlError> {
-    let redirect_uri = format!("{}auth/google/return", config.base_url);
+    let redirect_uri = format!("{}auth/google/return", base_url(&config, proto));
     let social_nounce = session.create_nounce();
 
     store.set("socialNounce", social_nounce.as_str());
 
     let nounce = session.create_nounce();
     let auth_url = url::Url::parse_with_params(
-        &*config.oauth_login_url.to_string(),
+        &config.oauth_login_url.to_string(),
         &[
             ("client_id", config.oauth_id.as_str()),
             ("response_type", "code"),
@@ -499,35 +849,53 @@ async fn auth_google(
 }
 
 async fn auth_google_return(
+    ClientIp(ip): ClientIp,
+    proto: ForwardedProto,
     State(config): State<Arc<Config>>,
+    State(rate_limiter): State<Arc<RateLimiter>>,
     store: SessionStore,
     Query(oauth): Query<auth::OauthResponse>,
 ) -> Result<impl IntoResponse, HtmlError> {
+    rate_limiter
+        .check(rate_limit::Group::Auth, &[rate_limit::Key::Ip(ip)])
+        .await?;
+
     let client = reqwest::Client::new();
-    let redirect_uri = format!("{}auth/google/return", config.base_url);
+    let redirect_uri = format!("{}auth/google/return", base_url(&config, proto));
     let nounce = store.get("socialNounce");
 
     if Some(oauth.state) != nounce {
+        metrics::metrics().login_failed();
         return Err(Error::Unauthorized.into());
     }
 
-    let raw_res = client
-        .post(&config.oauth_token_url.to_string())
-        .form(&auth::OauthTokenRequest {
-            code: &oauth.code,
-            client_id: &config.oauth_id,
-            client_secret: &config.oauth_secret,
-            redirect_uri: redirect_uri.as_str(),
-            grant_type: "authorization_code",
-        })
-        .send()
-        .await
-        .map_err(Error::from)?;
+    let token_res = async {
+        let raw_res = telemetry::propagate(client.post(config.oauth_token_url.to_string()))
+            .form(&auth::OauthTokenRequest {
+                code: &oauth.code,
+                client_id: &config.oauth_id,
+                client_secret: &config.oauth_secret,
+                redirect_uri: redirect_uri.as_str(),
+                grant_type: "authorization_code",
+            })
+            .send()
+            .await?;
+
+        raw_res.json::<auth::OauthTokenResponse>().await
+    }
+    .instrument(tracing::info_span!("oauth::token"))
+    .await;
 
-    let token_res = raw_res
-        .json::<auth::OauthTokenResponse>()
-        .await
-        .map_err(Error::from)?;
+    let token_res = match token_res {
+        Ok(token_res) => {
+            metrics::metrics().login_succeeded();
+            token_res
+        }
+        Err(err) => {
+            metrics::metrics().login_failed();
+            return Err(Error::from(err).into());
+        }
+    };
 
     store.set(
         "socialUser",
@@ -539,7 +907,7 @@ async fn auth_google_return(
     Ok((
         store,
         TypedHeader(no_cache),
-        Redirect::temporary(&config.base_url.to_string()),
+        Redirect::temporary(&base_url(&config, proto)),
     ))
 }
 
@@ -556,14 +924,17 @@ impl IntoResponse for HtmlError {
         let status = self.0.status_code();
 
         let html = if status == 404 {
-            views::not_found(None)
+            views::not_found(None, request_id::current())
         } else {
-            tracing::error!("server error: {}", self.0);
-            views::error(None, &self.0)
+            if status >= 500 {
+                tracing::error!("server error: {}", self.0);
+            }
+            views::error(None, &self.0, request_id::current())
         };
 
         let res = (
             self.0.status(),
+            retry_after(self.0.retry_after()),
             Html(html.unwrap_or("internal server error".to_string())),
         );
 
@@ -578,6 +949,7 @@ impl IntoResponse for JsonError {
         }
         let res = (
             StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            retry_after(self.retry_after),
             Json(self),
         );
 
@@ -585,15 +957,40 @@ impl IntoResponse for JsonError {
     }
 }
 
+fn retry_after(seconds: Option<u64>) -> Option<TypedHeader<headers::RetryAfter>> {
+    seconds.map(|seconds| {
+        TypedHeader(headers::RetryAfter::delay(std::time::Duration::from_secs(
+            seconds,
+        )))
+    })
+}
+
+/// Handlers taking the store may start a new session, so the new session
+/// budget is spent before the handler runs rather than once its work is done
 #[async_trait]
 impl axum::extract::FromRequestParts<ServerState> for SessionStore {
-    type Rejection = axum::extract::rejection::ExtensionRejection;
+    type Rejection = axum::response::Response;
 
     async fn from_request_parts(
         parts: &mut Parts,
-        _state: &ServerState,
+        state: &ServerState,
     ) -> Result<Self, Self::Rejection> {
-        let Extension(store) = parts.extract::<Extension<SessionStore>>().await?;
+        let Extension(store) = parts
+            .extract::<Extension<SessionStore>>()
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if store.is_created() {
+            let ClientIp(ip) = parts
+                .extract::<ClientIp>()
+                .await
+                .map_err(IntoResponse::into_response)?;
+            state
+                .rate_limiter
+                .check(rate_limit::Group::Session, &[rate_limit::Key::Ip(ip)])
+                .await
+                .map_err(|err| HtmlError(err).into_response())?;
+        }
 
         Ok(store)
     }
@@ -617,23 +1014,19 @@ where
             .await
             .ok();
 
-        let user = if let Some(db) = db {
-            let db = db.clone().get().await?;
-            let store = parts.extract::<Extension<SessionStore>>().await.ok();
-
-            if let Some(store) = store {
-                let id = store.get("socialUser");
-                if let Some(social_id) = id {
-                    let mut client = UserClient::new(db);
-                    client.get_social_user(social_id).await.map(Some)?
-                } else {
-                    None
-                }
-            } else {
-                None
+        let social_id = parts
+            .extract::<Extension<SessionStore>>()
+            .await
+            .ok()
+            .and_then(|store| store.get("socialUser"));
+
+        let user = match (db, social_id) {
+            (Some(db), Some(social_id)) => {
+                let db = db.clone().get().await?;
+                let mut client = UserClient::new(db);
+                client.get_social_user(social_id).await.map(Some)?
             }
-        } else {
-            None
+            _ => None,
         };
 
         let user = user.ok_or(Error::Unauthorized)?;
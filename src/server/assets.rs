@@ -0,0 +1,209 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+
+use super::reload::Reloadable;
+use super::Config;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static MANIFEST: RwLock<Option<Arc<AssetManifest>>> = RwLock::new(None);
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// The number of digest bytes in a fingerprint
+const HASH_LEN: usize = 8;
+
+/// Maps every file under the asset directory to a url containing a digest of
+/// its contents, `/css/bundle.css` becomes `/css/bundle.{hash}.css`
+#[derive(Debug, Default)]
+pub struct AssetManifest {
+    fingerprinted: HashMap<String, String>,
+    originals: HashMap<String, String>,
+    digest: String,
+    /// When this set of assets started being served, in milliseconds since
+    /// the epoch
+    installed: u64,
+}
+
+impl AssetManifest {
+    pub fn build(asset_dir: impl AsRef<Path>) -> std::io::Result<AssetManifest> {
+        let mut manifest = AssetManifest::default();
+        manifest.walk(asset_dir.as_ref(), "")?;
+
+        let mut urls: Vec<_> = manifest.originals.keys().collect();
+        urls.sort();
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        for url in urls {
+            context.update(url.as_bytes());
+            context.update(&[0]);
+        }
+        manifest.digest = base64::encode_config(context.finish(), base64::URL_SAFE_NO_PAD);
+
+        Ok(manifest)
+    }
+
+    fn walk(&mut self, dir: &Path, prefix: &str) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let path = format!("{}/{}", prefix, name);
+
+            if entry.file_type()?.is_dir() {
+                self.walk(&entry.path(), &path)?;
+            } else if name.ends_with(".br") || name.ends_with(".gz") {
+                // Precompressed siblings are found by ServeDir from the original path
+                continue;
+            } else {
+                let contents = std::fs::read(entry.path())?;
+                let digest = ring::digest::digest(&ring::digest::SHA256, &contents);
+                let hash: String = digest.as_ref()[..HASH_LEN]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+
+                let fingerprinted = fingerprint(&path, &hash);
+                self.originals.insert(fingerprinted.clone(), path.clone());
+                self.fingerprinted.insert(path, fingerprinted);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.fingerprinted.len()
+    }
+
+    /// The path for a url whose fingerprint is no longer current, as linked
+    /// from a page cached before the asset changed
+    fn outdated(&self, url: &str) -> Option<String> {
+        let (dir, file) = url.rsplit_once('/')?;
+        let mut parts: Vec<_> = file.split('.').collect();
+        let hash = parts.iter().skip(1).position(|part| is_hash(part))? + 1;
+        parts.remove(hash);
+
+        let path = format!("{}/{}", dir, parts.join("."));
+        self.fingerprinted.contains_key(&path).then_some(path)
+    }
+}
+
+fn is_hash(part: &str) -> bool {
+    part.len() == HASH_LEN * 2
+        && part
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn fingerprint(path: &str, hash: &str) -> String {
+    let (dir, file) = path.rsplit_once('/').unwrap_or(("", path));
+    match file.split_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}/{}.{}.{}", dir, stem, hash, ext),
+        _ => format!("{}/{}.{}", dir, file, hash),
+    }
+}
+
+/// Starts serving the assets in `manifest`, returning false and keeping the
+/// current manifest when the fingerprinted urls are unchanged
+pub fn install(mut manifest: AssetManifest) -> bool {
+    let mut current = MANIFEST.write().unwrap_or_else(|e| e.into_inner());
+    if current
+        .as_ref()
+        .is_some_and(|current| current.digest == manifest.digest)
+    {
+        return false;
+    }
+
+    manifest.installed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    *current = Some(Arc::new(manifest));
+    true
+}
+
+fn current() -> Option<Arc<AssetManifest>> {
+    MANIFEST.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// A digest of every fingerprinted url, pages linking to assets must be
+/// revalidated whenever it changes
+pub fn digest() -> String {
+    current()
+        .map(|manifest| manifest.digest.clone())
+        .unwrap_or_default()
+}
+
+/// When the current assets were installed, in milliseconds since the epoch
+pub fn installed() -> Option<u64> {
+    current().map(|manifest| manifest.installed)
+}
+
+/// The fingerprinted url for an asset, or the original path if the asset
+/// was not present when the manifest was built
+pub fn url(path: &str) -> String {
+    current()
+        .and_then(|manifest| manifest.fingerprinted.get(path).cloned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Rewrites requests for fingerprinted urls to the file on disk and marks the
+/// response as cacheable forever, the url changes whenever the file does. An
+/// outdated fingerprint is served the current file, but not cached forever.
+pub async fn serve_fingerprinted(mut req: Request<Body>, next: Next) -> Response {
+    let original = current().and_then(|manifest| {
+        let path = req.uri().path();
+        let (original, immutable) = match manifest.originals.get(path) {
+            Some(original) => (original.clone(), true),
+            None => (manifest.outdated(path)?, false),
+        };
+        Some((original.parse::<Uri>().ok()?, immutable))
+    });
+
+    let (original, immutable) = match original {
+        Some(original) => original,
+        None => return next.run(req).await,
+    };
+
+    *req.uri_mut() = original;
+    let mut res = next.run(req).await;
+    if immutable && res.status().is_success() {
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE));
+    }
+
+    res
+}
+
+/// Serves files from the asset directory, precompressed siblings are used
+/// when compression is enabled
+pub fn serve_dir(config: &Config) -> ServeDir {
+    let serve_dir =
+        ServeDir::new(config.asset_dir.as_str()).append_index_html_on_directories(false);
+    if config.compression {
+        serve_dir.precompressed_br().precompressed_gzip()
+    } else {
+        serve_dir
+    }
+}
+
+/// Serves a static file from the current asset directory, which changes
+/// when the config is reloaded
+pub async fn serve(State(serve_dir): State<Reloadable<ServeDir>>, req: Request<Body>) -> Response {
+    let serve_dir = ServeDir::clone(&serve_dir.current());
+    match serve_dir.oneshot(req).await {
+        Ok(res) => res.map(Body::new),
+        Err(err) => match err {},
+    }
+}
@@ -0,0 +1,190 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::{self, HeaderMapExt};
+
+use super::assets;
+
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+
+/// Changes to the server that alter rendered pages without touching posts
+/// must also change every ETag, each build of the templates is a new version
+const VALIDATOR_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "+",
+    env!("BUILD_GIT_HASH"),
+    ".",
+    env!("BUILD_TIME")
+);
+
+#[derive(Debug, Clone)]
+pub struct Validators {
+    etag: headers::ETag,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Pages link to fingerprinted assets, so both validators move on when
+    /// a different set of assets is installed
+    pub fn builder(kind: &str) -> ValidatorsBuilder {
+        ValidatorsBuilder {
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            last_modified: assets::installed(),
+        }
+        .add(VALIDATOR_VERSION)
+        .add(assets::digest())
+        .add(kind)
+    }
+}
+
+pub struct ValidatorsBuilder {
+    context: ring::digest::Context,
+    last_modified: Option<u64>,
+}
+
+impl ValidatorsBuilder {
+    pub fn add(mut self, value: impl Display) -> Self {
+        self.context.update(value.to_string().as_bytes());
+        self.context.update(&[0]);
+        self
+    }
+
+    /// Records a modification time in milliseconds since the epoch, the
+    /// latest one added becomes the Last-Modified date
+    pub fn modified(mut self, millis: u64) -> Self {
+        self.last_modified = self.last_modified.max(Some(millis));
+        self
+    }
+
+    pub fn build(self) -> Validators {
+        let digest = base64::encode_config(self.context.finish(), base64::URL_SAFE_NO_PAD);
+        // Weak as the same page may be sent compressed or not, and with a
+        // fresh csp nonce every time
+        let etag = format!("W/\"{}\"", &digest[..27])
+            .parse()
+            .expect("Base64 digest is a valid etag");
+        let last_modified = self
+            .last_modified
+            .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis));
+
+        Validators {
+            etag,
+            last_modified,
+        }
+    }
+}
+
+/// The validators sent by the client with a conditional GET
+#[derive(Debug, Default)]
+pub struct Conditional {
+    if_none_match: Option<headers::IfNoneMatch>,
+    if_modified_since: Option<headers::IfModifiedSince>,
+}
+
+impl Conditional {
+    pub fn not_modified(&self, validators: &Validators) -> bool {
+        if let Some(if_none_match) = self.if_none_match.as_ref() {
+            !if_none_match.precondition_passes(&validators.etag)
+        } else if let Some(if_modified_since) = self.if_modified_since.as_ref() {
+            validators
+                .last_modified
+                .map(|modified| !if_modified_since.is_modified(modified))
+                .unwrap_or(false)
+        } else {
+            false
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Conditional {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Conditional {
+            if_none_match: parts.headers.typed_get(),
+            if_modified_since: parts.headers.typed_get(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CachePolicy {
+    /// Identical for every visitor, may be stored by shared caches
+    Public,
+    /// Personalized for a logged in user, must always be revalidated
+    Private,
+}
+
+impl CachePolicy {
+    pub fn for_user<T>(user: Option<&T>) -> CachePolicy {
+        if user.is_some() {
+            CachePolicy::Private
+        } else {
+            CachePolicy::Public
+        }
+    }
+
+    fn header(&self) -> HeaderValue {
+        match self {
+            CachePolicy::Public => HeaderValue::from_static("public, max-age=60"),
+            CachePolicy::Private => HeaderValue::from_static("private, no-cache"),
+        }
+    }
+}
+
+/// A response carrying validators that collapses to a bodiless 304 when the
+/// client's copy is still current
+pub struct Cached<T> {
+    validators: Validators,
+    policy: CachePolicy,
+    body: Option<T>,
+}
+
+impl<T> Cached<T> {
+    pub fn fresh(validators: Validators, policy: CachePolicy, body: T) -> Cached<T> {
+        Cached {
+            validators,
+            policy,
+            body: Some(body),
+        }
+    }
+
+    pub fn not_modified(validators: Validators, policy: CachePolicy) -> Cached<T> {
+        Cached {
+            validators,
+            policy,
+            body: None,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Cached<U> {
+        Cached {
+            validators: self.validators,
+            policy: self.policy,
+            body: self.body.map(f),
+        }
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Cached<T> {
+    fn into_response(self) -> Response {
+        let mut res = match self.body {
+            Some(body) => body.into_response(),
+            None => StatusCode::NOT_MODIFIED.into_response(),
+        };
+
+        let headers = res.headers_mut();
+        headers.typed_insert(self.validators.etag);
+        if let Some(modified) = self.validators.last_modified {
+            headers.typed_insert(headers::LastModified::from(modified));
+        }
+        headers.insert(header::CACHE_CONTROL, self.policy.header());
+        headers.insert(header::VARY, HeaderValue::from_static("Cookie"));
+
+        res
+    }
+}
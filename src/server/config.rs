@@ -1,15 +1,35 @@
 use http::Uri;
+use ipnet::IpNet;
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use structopt::{clap, StructOpt};
 
-use std::fs::File;
-use std::io::Read;
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
 type Bytes = Vec<u8>;
 
+/// Environment variables starting with this set the config key of the same
+/// name, e.g. `NICKMASS_LISTEN_PORT`
+const ENV_PREFIX: &str = "NICKMASS_";
+/// Environment variables ending with this name a file holding the value,
+/// e.g. `NICKMASS_OAUTH_SECRET_FILE`
+const ENV_FILE_SUFFIX: &str = "_FILE";
+const DEFAULT_CONFIG_FILE: &str = "./config.toml";
+const REDACTED: &str = "<redacted>";
+const DEFAULT_ASSET_DIR: &str = "public";
+const SESSION_KEY_LEN: usize = 32;
+const REDIS_SCHEMES: &[&str] = &["redis", "rediss", "redis+unix", "unix"];
+/// Files the page templates link to, the server is not much use without them
+const REQUIRED_ASSETS: &[&str] = &["css/bundle.css", "js/main.js"];
+
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+const DEFAULT_PERMISSIONS_POLICY: &str =
+    "camera=(), microphone=(), geolocation=(), payment=(), usb=()";
+
 #[derive(Debug, Default, StructOpt, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigBuilder {
     #[serde(deserialize_with = "deserialize_base64")]
     #[serde(serialize_with = "serialize_base64")]
@@ -52,6 +72,10 @@ pub struct ConfigBuilder {
     /// The port to listen on [default: 80]
     pub listen_port: Option<u16>,
     #[serde(default)]
+    #[structopt(long = "listen", number_of_values = 1)]
+    /// Addresses to serve the website on instead of ip and port, each one of ip:port, [ipv6]:port, unix:/path/to.sock or systemd for sockets passed with LISTEN_FDS
+    pub listen: Option<Vec<ListenAddr>>,
+    #[serde(default)]
     #[serde(deserialize_with = "deserialize_uri")]
     #[serde(serialize_with = "serialize_uri")]
     #[structopt(short = "r", long = "redis")]
@@ -61,10 +85,153 @@ pub struct ConfigBuilder {
     #[structopt(short = "a", long = "assets")]
     /// The directory to serve website static assets from
     pub asset_dir: Option<String>,
+    #[serde(default)]
+    #[structopt(long = "raw_html_role")]
+    /// The user roles allowed to publish unsanitized html in posts [default: admin]
+    pub raw_html_roles: Option<Vec<String>>,
+    #[serde(default)]
+    #[structopt(long = "render_cache_size")]
+    /// The number of rendered posts to keep in memory, 0 disables [default: 256]
+    pub render_cache_size: Option<usize>,
+    #[serde(default)]
+    #[structopt(long = "render_cache_redis")]
+    /// Share rendered posts between servers through redis [default: false]
+    pub render_cache_redis: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "render_math")]
+    /// Render $...$ and $$...$$ in posts as MathML, otherwise dollar signs are plain text [default: false]
+    pub render_math: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "compression")]
+    /// Compress responses for clients that accept it [default: true]
+    pub compression: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "tls_cert")]
+    /// The PEM certificate chain to serve https with, requires tls_key
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    #[structopt(long = "tls_key")]
+    /// The PEM private key for tls_cert
+    pub tls_key: Option<PathBuf>,
+    #[serde(default)]
+    #[structopt(long = "redirect_port")]
+    /// Listen for plain http on this port and redirect it to https
+    pub redirect_port: Option<u16>,
+    #[serde(default)]
+    #[structopt(long = "keep_alive")]
+    /// Reuse connections for multiple requests [default: true]
+    pub keep_alive: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "keep_alive_interval")]
+    /// Seconds between http2 keep alive pings, 0 disables [default: 60]
+    pub keep_alive_interval: Option<u64>,
+    #[serde(default)]
+    #[structopt(long = "header_read_timeout")]
+    /// Seconds allowed for a client to send request headers [default: 30]
+    pub header_read_timeout: Option<u64>,
+    #[serde(default)]
+    #[structopt(long = "max_concurrent_streams")]
+    /// The number of concurrent http2 requests per connection [default: 200]
+    pub max_concurrent_streams: Option<u32>,
+    #[serde(default)]
+    #[structopt(long = "drain_timeout")]
+    /// Seconds to wait for open connections to finish when shutting down [default: 30]
+    pub drain_timeout: Option<u64>,
+    #[serde(default)]
+    #[structopt(long = "metrics_port")]
+    /// Serve /metrics on this port instead of alongside the website
+    pub metrics_port: Option<u16>,
+    #[serde(default)]
+    #[structopt(long = "log_format")]
+    /// The format of log lines, one of full, compact, pretty or json [default: full]
+    pub log_format: Option<LogFormat>,
+    #[serde(default)]
+    #[structopt(long = "log_filter")]
+    /// A RUST_LOG style filter directive, overrides -v and -s when set
+    pub log_filter: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_uri")]
+    #[serde(serialize_with = "serialize_uri")]
+    #[structopt(long = "otlp_endpoint")]
+    /// Export traces to this OTLP/HTTP collector, e.g. http://localhost:4318
+    pub otlp_endpoint: Option<Uri>,
+    #[serde(default)]
+    #[structopt(long = "otlp_sample_ratio")]
+    /// The fraction of new traces to export, between 0.0 and 1.0 [default: 1.0]
+    pub otlp_sample_ratio: Option<f64>,
+    #[serde(default)]
+    #[structopt(long = "rate_limit")]
+    /// Limit how quickly a client can log in, start sessions, write posts and report csp violations [default: true]
+    pub rate_limit: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "rate_limit_auth")]
+    /// Oauth logins allowed per ip, as requests/seconds [default: 10/300]
+    pub rate_limit_auth: Option<RateBudget>,
+    #[serde(default)]
+    #[structopt(long = "rate_limit_session")]
+    /// New sessions allowed per ip, as requests/seconds [default: 60/60]
+    pub rate_limit_session: Option<RateBudget>,
+    #[serde(default)]
+    #[structopt(long = "rate_limit_api_write")]
+    /// Post creates, updates and deletes allowed per ip and per user, as requests/seconds [default: 30/60]
+    pub rate_limit_api_write: Option<RateBudget>,
+    #[serde(default)]
+    #[structopt(long = "rate_limit_csp_report")]
+    /// Content security policy violation reports accepted per ip, as requests/seconds [default: 20/60]
+    pub rate_limit_csp_report: Option<RateBudget>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_nets")]
+    #[structopt(long = "trusted_proxy", parse(try_from_str = parse_net))]
+    /// Addresses or CIDR ranges of reverse proxies whose forwarding headers are believed
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    #[serde(default)]
+    #[structopt(long = "csp_frame_src")]
+    /// Sources pages may embed frames from [default: https://www.youtube.com https://player.vimeo.com]
+    pub csp_frame_src: Option<Vec<String>>,
+    #[serde(default)]
+    #[structopt(long = "csp_img_src")]
+    /// Sources other than this site that pages may load images from [default: https://img.youtube.com]
+    pub csp_img_src: Option<Vec<String>>,
+    #[serde(default)]
+    #[structopt(long = "csp_report")]
+    /// Ask browsers to send policy violations to /csp-report [default: true]
+    pub csp_report: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "csp_report_only")]
+    /// Report policy violations without enforcing the policy [default: false]
+    pub csp_report_only: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "security_headers")]
+    /// Send hardening headers such as HSTS and Referrer-Policy [default: true]
+    pub security_headers: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "hsts_max_age")]
+    /// Seconds browsers should only use https, sent on secure connections, 0 disables [default: 31536000]
+    pub hsts_max_age: Option<u64>,
+    #[serde(default)]
+    #[structopt(long = "hsts_include_subdomains")]
+    /// Extend HSTS to every subdomain [default: false]
+    pub hsts_include_subdomains: Option<bool>,
+    #[serde(default)]
+    #[structopt(long = "referrer_policy")]
+    /// The Referrer-Policy for pages and api responses, empty disables [default: strict-origin-when-cross-origin]
+    pub referrer_policy: Option<String>,
+    #[serde(default)]
+    #[structopt(long = "permissions_policy")]
+    /// The Permissions-Policy for pages, empty disables [default: camera=(), microphone=(), geolocation=(), payment=(), usb=()]
+    pub permissions_policy: Option<String>,
+    #[serde(default)]
+    #[structopt(long = "cross_origin_opener_policy")]
+    /// The Cross-Origin-Opener-Policy for pages, empty disables [default: same-origin]
+    pub cross_origin_opener_policy: Option<String>,
+    #[serde(default)]
+    #[structopt(long = "cross_origin_resource_policy")]
+    /// The Cross-Origin-Resource-Policy for every response, empty disables [default: same-origin]
+    pub cross_origin_resource_policy: Option<String>,
     #[serde(skip)]
-    #[structopt(short = "c", long = "config", default_value = "./config.toml")]
-    /// The config file to load default settings from
-    pub config_file: PathBuf,
+    #[structopt(short = "c", long = "config", env = "NICKMASS_CONFIG")]
+    /// The config file to load default settings from, optional unless given explicitly [default: ./config.toml]
+    pub config_file: Option<PathBuf>,
     #[serde(skip)]
     #[structopt(short = "v", parse(from_occurrences))]
     /// The verbosity level of logging
@@ -82,22 +249,271 @@ pub struct ConfigBuilder {
 pub enum Subcommand {
     #[structopt(name = "config")]
     /// Generate an example config.toml file
-    GenerateConfig,
+    Config {
+        #[structopt(subcommand)]
+        cmd: Option<ConfigCommand>,
+    },
+}
+
+#[derive(Debug, StructOpt, Serialize, Deserialize)]
+pub enum ConfigCommand {
+    #[structopt(name = "check")]
+    /// Print the effective config with secrets redacted. Settings are taken
+    /// from arguments first, then NICKMASS_* environment variables, then the
+    /// config file
+    Check,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format '{}', expected full, compact, pretty or json",
+                s
+            )),
+        }
+    }
+}
+
+/// A token bucket holding `requests` tokens which refills completely over
+/// `seconds`, written as `requests/seconds`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateBudget {
+    pub requests: u32,
+    pub seconds: u32,
+}
+
+impl RateBudget {
+    pub const fn new(requests: u32, seconds: u32) -> RateBudget {
+        RateBudget { requests, seconds }
+    }
+}
+
+impl std::str::FromStr for RateBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid rate budget '{}', expected requests/seconds such as 30/60",
+                s
+            )
+        };
+        let (requests, seconds) = s.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds = seconds.trim().parse().map_err(|_| invalid())?;
+
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+
+        Ok(RateBudget { requests, seconds })
+    }
+}
+
+impl TryFrom<String> for RateBudget {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RateBudget> for String {
+    fn from(budget: RateBudget) -> String {
+        format!("{}/{}", budget.requests, budget.seconds)
+    }
+}
+
+/// Somewhere to accept website connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Every socket systemd passed to the process through socket activation
+    Systemd,
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "systemd" {
+            Ok(ListenAddr::Systemd)
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            s.parse().map(ListenAddr::Tcp).map_err(|_| {
+                format!(
+                    "invalid listen address '{}', expected ip:port, [ipv6]:port, unix:/path or systemd",
+                    s
+                )
+            })
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd => write!(f, "systemd"),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> String {
+        addr.to_string()
+    }
 }
 
 impl ConfigBuilder {
-    fn build(self) -> Result<Config, &'static str> {
+    fn build(self) -> Result<Config, Vec<ConfigError>> {
+        let required = [
+            ("session_key", self.session_key.is_some()),
+            ("base_url", self.base_url.is_some()),
+            ("oauth_login_url", self.oauth_login_url.is_some()),
+            ("oauth_token_url", self.oauth_token_url.is_some()),
+            ("oauth_id", self.oauth_id.is_some()),
+            ("oauth_secret", self.oauth_secret.is_some()),
+            ("redis_url", self.redis_url.is_some()),
+        ];
+        let mut errors: Vec<_> = required
+            .into_iter()
+            .filter(|(_, present)| !present)
+            .map(|(key, _)| ConfigError::missing(key))
+            .collect();
+
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => {
+                errors.push(ConfigError::new("tls_key", "required when tls_cert is set"))
+            }
+            (None, Some(_)) => {
+                errors.push(ConfigError::new("tls_cert", "required when tls_key is set"))
+            }
+            _ => (),
+        }
+
+        let (
+            Some(session_key),
+            Some(base_url),
+            Some(oauth_login_url),
+            Some(oauth_token_url),
+            Some(oauth_id),
+            Some(oauth_secret),
+            Some(redis_url),
+        ) = (
+            self.session_key,
+            self.base_url,
+            self.oauth_login_url,
+            self.oauth_token_url,
+            self.oauth_id,
+            self.oauth_secret,
+            self.redis_url,
+        )
+        else {
+            return Err(errors);
+        };
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let listen_ip = self.listen_ip.unwrap_or([0, 0, 0, 0].into());
+        let listen_port = self.listen_port.unwrap_or(80);
+        let listen = self
+            .listen
+            .filter(|listen| !listen.is_empty())
+            .unwrap_or_else(|| vec![ListenAddr::Tcp(SocketAddr::new(listen_ip, listen_port))]);
+
         let config = Config {
-            session_key: self.session_key.ok_or_else(|| "session_key")?,
-            base_url: self.base_url.ok_or_else(|| "base_url")?,
-            oauth_login_url: self.oauth_login_url.ok_or_else(|| "oauth_login_url")?,
-            oauth_token_url: self.oauth_token_url.ok_or_else(|| "oauth_token_url")?,
-            oauth_id: self.oauth_id.ok_or_else(|| "oauth_id")?,
-            oauth_secret: self.oauth_secret.ok_or_else(|| "oauth_secret")?,
-            listen_ip: self.listen_ip.unwrap_or([0, 0, 0, 0].into()),
-            listen_port: self.listen_port.unwrap_or(80),
-            redis_url: self.redis_url.ok_or_else(|| "redis_url")?,
-            asset_dir: self.asset_dir.unwrap_or("public".to_string()),
+            session_key,
+            base_url,
+            oauth_login_url,
+            oauth_token_url,
+            oauth_id,
+            oauth_secret,
+            listen_ip,
+            listen_port,
+            listen,
+            redis_url,
+            asset_dir: self
+                .asset_dir
+                .unwrap_or_else(|| DEFAULT_ASSET_DIR.to_string()),
+            raw_html_roles: self
+                .raw_html_roles
+                .unwrap_or_else(|| vec!["admin".to_string()]),
+            render_cache_size: self.render_cache_size.unwrap_or(256),
+            render_cache_redis: self.render_cache_redis.unwrap_or(false),
+            render_math: self.render_math.unwrap_or(false),
+            compression: self.compression.unwrap_or(true),
+            tls_cert: self.tls_cert,
+            tls_key: self.tls_key,
+            redirect_port: self.redirect_port,
+            keep_alive: self.keep_alive.unwrap_or(true),
+            keep_alive_interval: self.keep_alive_interval.unwrap_or(60),
+            header_read_timeout: self.header_read_timeout.unwrap_or(30),
+            max_concurrent_streams: self.max_concurrent_streams.unwrap_or(200),
+            drain_timeout: self.drain_timeout.unwrap_or(30),
+            metrics_port: self.metrics_port,
+            log_format: self.log_format.unwrap_or_default(),
+            log_filter: self.log_filter,
+            otlp_endpoint: self.otlp_endpoint,
+            otlp_sample_ratio: self.otlp_sample_ratio.unwrap_or(1.0),
+            rate_limit: self.rate_limit.unwrap_or(true),
+            rate_limit_auth: self.rate_limit_auth.unwrap_or(RateBudget::new(10, 300)),
+            rate_limit_session: self.rate_limit_session.unwrap_or(RateBudget::new(60, 60)),
+            rate_limit_api_write: self.rate_limit_api_write.unwrap_or(RateBudget::new(30, 60)),
+            rate_limit_csp_report: self
+                .rate_limit_csp_report
+                .unwrap_or(RateBudget::new(20, 60)),
+            trusted_proxies: self.trusted_proxies.unwrap_or_default(),
+            csp_frame_src: self.csp_frame_src.unwrap_or_else(default_csp_frame_src),
+            csp_img_src: self.csp_img_src.unwrap_or_else(default_csp_img_src),
+            csp_report: self.csp_report.unwrap_or(true),
+            csp_report_only: self.csp_report_only.unwrap_or(false),
+            security_headers: self.security_headers.unwrap_or(true),
+            hsts_max_age: self.hsts_max_age.unwrap_or(31536000),
+            hsts_include_subdomains: self.hsts_include_subdomains.unwrap_or(false),
+            referrer_policy: self
+                .referrer_policy
+                .unwrap_or_else(|| DEFAULT_REFERRER_POLICY.to_string()),
+            permissions_policy: self
+                .permissions_policy
+                .unwrap_or_else(|| DEFAULT_PERMISSIONS_POLICY.to_string()),
+            cross_origin_opener_policy: self
+                .cross_origin_opener_policy
+                .unwrap_or_else(|| "same-origin".to_string()),
+            cross_origin_resource_policy: self
+                .cross_origin_resource_policy
+                .unwrap_or_else(|| "same-origin".to_string()),
             verbosity: self.verbosity,
             silent: self.silent,
         };
@@ -105,6 +521,348 @@ impl ConfigBuilder {
         Ok(config)
     }
 
+    /// Every setting with its default or an example value, the session key
+    /// is freshly generated so the sample is usable as it is
+    fn sample() -> ConfigBuilder {
+        let mut session_key = vec![0; SESSION_KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut session_key)
+            .unwrap_or_else(|_| {
+                config_err("Unable to generate a session key", clap::ErrorKind::Io)
+            });
+
+        ConfigBuilder {
+            session_key: Some(session_key),
+            base_url: Uri::from_static("http://example.com").into(),
+            oauth_login_url: Uri::from_static("http://example.com").into(),
+            oauth_token_url: Uri::from_static("http://example.com").into(),
+            oauth_id: Some("oauth_id".into()),
+            oauth_secret: Some("oauth_secret".into()),
+            listen_ip: Some([0, 0, 0, 0].into()),
+            listen_port: 80.into(),
+            redis_url: Uri::from_static("redis://server:port/db").into(),
+            asset_dir: Some(DEFAULT_ASSET_DIR.into()),
+            raw_html_roles: Some(vec!["admin".into()]),
+            render_cache_size: Some(256),
+            render_cache_redis: Some(false),
+            render_math: Some(false),
+            compression: Some(true),
+            keep_alive: Some(true),
+            keep_alive_interval: Some(60),
+            header_read_timeout: Some(30),
+            max_concurrent_streams: Some(200),
+            drain_timeout: Some(30),
+            log_format: Some(LogFormat::Full),
+            otlp_sample_ratio: Some(1.0),
+            rate_limit: Some(true),
+            rate_limit_auth: Some(RateBudget::new(10, 300)),
+            rate_limit_session: Some(RateBudget::new(60, 60)),
+            rate_limit_api_write: Some(RateBudget::new(30, 60)),
+            rate_limit_csp_report: Some(RateBudget::new(20, 60)),
+            trusted_proxies: Some(vec![]),
+            csp_frame_src: Some(default_csp_frame_src()),
+            csp_img_src: Some(default_csp_img_src()),
+            csp_report: Some(true),
+            csp_report_only: Some(false),
+            security_headers: Some(true),
+            hsts_max_age: Some(31536000),
+            hsts_include_subdomains: Some(false),
+            referrer_policy: Some(DEFAULT_REFERRER_POLICY.into()),
+            permissions_policy: Some(DEFAULT_PERMISSIONS_POLICY.into()),
+            cross_origin_opener_policy: Some("same-origin".into()),
+            cross_origin_resource_policy: Some("same-origin".into()),
+            ..Default::default()
+        }
+    }
+
+    /// The keys of every setting that has been given a value
+    fn keys(&self) -> Vec<String> {
+        toml::Value::try_from(self)
+            .ok()
+            .and_then(|value| value.as_table().map(|t| t.keys().cloned().collect()))
+            .unwrap_or_default()
+    }
+
+    /// Reads `NICKMASS_*` variables, a variable ending in `_FILE` names a
+    /// file to read the value from instead, which suits mounted secrets. A
+    /// variable holding the value directly wins over its `_FILE` variant.
+    /// Variables that match no setting are reported so typos are not lost.
+    fn from_env(vars: impl Iterator<Item = (String, String)>) -> Layer {
+        let mut values = HashMap::new();
+        let mut files = Vec::new();
+        let mut layer = Layer::default();
+
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            // Consumed by the argument parser to pick the config file
+            if key == "CONFIG" {
+                continue;
+            }
+            if key == format!("CONFIG{}", ENV_FILE_SUFFIX) {
+                layer.errors.push(
+                    ConfigError {
+                        key: None,
+                        message: format!("the config file is chosen with {}CONFIG", ENV_PREFIX),
+                        source: None,
+                    }
+                    .with_source(Source::Env(name)),
+                );
+                continue;
+            }
+            match key.strip_suffix(ENV_FILE_SUFFIX) {
+                Some(key) => files.push((key.to_lowercase(), name.clone(), value)),
+                None => {
+                    values.insert(key.to_lowercase(), (name.clone(), value));
+                }
+            }
+        }
+
+        for (key, name, path) in files {
+            if values.contains_key(&key) {
+                continue;
+            }
+            match std::fs::read_to_string(&path) {
+                Ok(value) => {
+                    let value = value.trim_end_matches(['\r', '\n']).to_string();
+                    values.insert(key, (name, value));
+                }
+                Err(e) => layer.errors.push(
+                    ConfigError::new(key, format!("unable to read {}: {}", path, e))
+                        .with_source(Source::Env(name)),
+                ),
+            }
+        }
+
+        // Each variable is parsed alone so every bad value can be reported
+        for (key, (name, value)) in values {
+            let source = Source::Env(name);
+            match envy::from_iter::<_, ConfigBuilder>([(key.clone(), value)]) {
+                Ok(settings) => {
+                    layer.settings = settings.merge(std::mem::take(&mut layer.settings));
+                    layer.sources.push((key, source));
+                }
+                Err(e) => layer
+                    .errors
+                    .push(ConfigError::new(key, e.to_string()).with_source(source)),
+            }
+        }
+
+        layer
+    }
+
+    /// Loads the given config file, or ./config.toml when none was given and
+    /// it exists. Keys that match no setting are reported as errors.
+    fn from_file(path: Option<&PathBuf>) -> Layer {
+        let mut layer = Layer::default();
+        let path = match path {
+            Some(path) => path.clone(),
+            None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                PathBuf::from(DEFAULT_CONFIG_FILE)
+            }
+            None => return layer,
+        };
+        let file_error = |message: String| ConfigError {
+            key: None,
+            message,
+            source: Some(Source::File(path.clone(), None)),
+        };
+
+        let config_file = match std::fs::read_to_string(&path) {
+            Ok(config_file) => config_file,
+            Err(e) => {
+                layer
+                    .errors
+                    .push(file_error(format!("unable to read config file: {}", e)));
+                return layer;
+            }
+        };
+        let table = match toml::from_str::<toml::value::Table>(&config_file) {
+            Ok(table) => table,
+            Err(e) => {
+                layer
+                    .errors
+                    .push(file_error(format!("unable to parse config file: {}", e)));
+                return layer;
+            }
+        };
+
+        // Each key is parsed alone so every bad value can be reported
+        for (key, value) in table {
+            let source = Source::File(path.clone(), line_of(&config_file, &key));
+            let mut entry = toml::value::Table::new();
+            entry.insert(key.clone(), value);
+            match toml::Value::Table(entry).try_into::<ConfigBuilder>() {
+                Ok(settings) => {
+                    layer.settings = settings.merge(std::mem::take(&mut layer.settings));
+                    layer.sources.push((key, source));
+                }
+                Err(e) => layer
+                    .errors
+                    .push(ConfigError::new(key, e.to_string()).with_source(source)),
+            }
+        }
+
+        layer
+    }
+
+    /// Checks the values that are set, missing values are left to `build`
+    fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |key: &str, valid: bool, message: String| {
+            if !valid {
+                errors.push(ConfigError::new(key, message));
+            }
+        };
+
+        if let Some(session_key) = &self.session_key {
+            check(
+                "session_key",
+                session_key.len() == SESSION_KEY_LEN,
+                format!(
+                    "must be {} bytes encoded as base64, got {} bytes",
+                    SESSION_KEY_LEN,
+                    session_key.len()
+                ),
+            );
+        }
+
+        let is_absolute = |url: &Uri| {
+            matches!(url.scheme_str(), Some("http" | "https")) && url.authority().is_some()
+        };
+        if let Some(base_url) = &self.base_url {
+            check(
+                "base_url",
+                is_absolute(base_url),
+                format!("'{}' must be an absolute http or https url", base_url),
+            );
+            check(
+                "base_url",
+                base_url.path().ends_with('/'),
+                format!("'{}' must end with '/'", base_url),
+            );
+        }
+        for (key, url) in [
+            ("oauth_login_url", &self.oauth_login_url),
+            ("oauth_token_url", &self.oauth_token_url),
+        ] {
+            if let Some(url) = url {
+                check(
+                    key,
+                    is_absolute(url),
+                    format!("'{}' must be an absolute http or https url", url),
+                );
+            }
+        }
+
+        if let Some(redis_url) = &self.redis_url {
+            let scheme = redis_url.scheme_str().unwrap_or("");
+            check(
+                "redis_url",
+                REDIS_SCHEMES.contains(&scheme),
+                format!(
+                    "unsupported scheme '{}', expected one of {}",
+                    scheme,
+                    REDIS_SCHEMES.join(", ")
+                ),
+            );
+        }
+
+        let asset_dir = self.asset_dir.as_deref().unwrap_or(DEFAULT_ASSET_DIR);
+        match std::fs::read_dir(asset_dir) {
+            Ok(_) => {
+                let missing: Vec<_> = REQUIRED_ASSETS
+                    .iter()
+                    .filter(|asset| !std::path::Path::new(asset_dir).join(asset).is_file())
+                    .copied()
+                    .collect();
+                check(
+                    "asset_dir",
+                    missing.is_empty(),
+                    format!(
+                        "'{}' is missing {}, build the assets first",
+                        asset_dir,
+                        missing.join(", ")
+                    ),
+                );
+            }
+            Err(e) => check(
+                "asset_dir",
+                false,
+                format!("unable to read '{}': {}", asset_dir, e),
+            ),
+        }
+
+        for (key, path) in [("tls_cert", &self.tls_cert), ("tls_key", &self.tls_key)] {
+            if let Some(path) = path {
+                check(
+                    key,
+                    path.is_file(),
+                    format!("'{}' does not exist", path.display()),
+                );
+            }
+        }
+
+        for addr in self.listen.iter().flatten() {
+            if let ListenAddr::Unix(path) = addr {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                check(
+                    "listen",
+                    dir.is_none_or(|dir| dir.is_dir()),
+                    format!("the directory for '{}' does not exist", path.display()),
+                );
+            }
+        }
+
+        let systemd = self
+            .listen
+            .iter()
+            .flatten()
+            .filter(|addr| matches!(addr, ListenAddr::Systemd))
+            .count();
+        if systemd > 0 {
+            // The passed sockets can only be taken once
+            check(
+                "listen",
+                systemd == 1,
+                "systemd may only be listed once".to_string(),
+            );
+
+            let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+            check(
+                "listen",
+                var("LISTEN_PID") == Some(std::process::id()),
+                "systemd requires LISTEN_PID to name this process".to_string(),
+            );
+            check(
+                "listen",
+                var("LISTEN_FDS").is_some_and(|count| count > 0),
+                "systemd requires sockets passed in LISTEN_FDS by socket activation".to_string(),
+            );
+        }
+
+        if let Some(directive) = &self.log_filter {
+            if let Err(e) = tracing_subscriber::EnvFilter::try_new(directive) {
+                check(
+                    "log_filter",
+                    false,
+                    format!("'{}' is invalid: {}", directive, e),
+                );
+            }
+        }
+
+        if let Some(ratio) = self.otlp_sample_ratio {
+            check(
+                "otlp_sample_ratio",
+                (0.0..=1.0).contains(&ratio),
+                format!("{} must be between 0.0 and 1.0", ratio),
+            );
+        }
+
+        errors
+    }
+
     fn merge(self, other: ConfigBuilder) -> ConfigBuilder {
         ConfigBuilder {
             session_key: self.session_key.or(other.session_key),
@@ -115,8 +873,50 @@ impl ConfigBuilder {
             oauth_secret: self.oauth_secret.or(other.oauth_secret),
             listen_ip: self.listen_ip.or(other.listen_ip),
             listen_port: self.listen_port.or(other.listen_port),
+            listen: self.listen.or(other.listen),
             redis_url: self.redis_url.or(other.redis_url),
             asset_dir: self.asset_dir.or(other.asset_dir),
+            raw_html_roles: self.raw_html_roles.or(other.raw_html_roles),
+            render_cache_size: self.render_cache_size.or(other.render_cache_size),
+            render_cache_redis: self.render_cache_redis.or(other.render_cache_redis),
+            render_math: self.render_math.or(other.render_math),
+            compression: self.compression.or(other.compression),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            redirect_port: self.redirect_port.or(other.redirect_port),
+            keep_alive: self.keep_alive.or(other.keep_alive),
+            keep_alive_interval: self.keep_alive_interval.or(other.keep_alive_interval),
+            header_read_timeout: self.header_read_timeout.or(other.header_read_timeout),
+            max_concurrent_streams: self.max_concurrent_streams.or(other.max_concurrent_streams),
+            drain_timeout: self.drain_timeout.or(other.drain_timeout),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            log_format: self.log_format.or(other.log_format),
+            log_filter: self.log_filter.or(other.log_filter),
+            otlp_endpoint: self.otlp_endpoint.or(other.otlp_endpoint),
+            otlp_sample_ratio: self.otlp_sample_ratio.or(other.otlp_sample_ratio),
+            rate_limit: self.rate_limit.or(other.rate_limit),
+            rate_limit_auth: self.rate_limit_auth.or(other.rate_limit_auth),
+            rate_limit_session: self.rate_limit_session.or(other.rate_limit_session),
+            rate_limit_api_write: self.rate_limit_api_write.or(other.rate_limit_api_write),
+            rate_limit_csp_report: self.rate_limit_csp_report.or(other.rate_limit_csp_report),
+            trusted_proxies: self.trusted_proxies.or(other.trusted_proxies),
+            csp_frame_src: self.csp_frame_src.or(other.csp_frame_src),
+            csp_img_src: self.csp_img_src.or(other.csp_img_src),
+            csp_report: self.csp_report.or(other.csp_report),
+            csp_report_only: self.csp_report_only.or(other.csp_report_only),
+            security_headers: self.security_headers.or(other.security_headers),
+            hsts_max_age: self.hsts_max_age.or(other.hsts_max_age),
+            hsts_include_subdomains: self
+                .hsts_include_subdomains
+                .or(other.hsts_include_subdomains),
+            referrer_policy: self.referrer_policy.or(other.referrer_policy),
+            permissions_policy: self.permissions_policy.or(other.permissions_policy),
+            cross_origin_opener_policy: self
+                .cross_origin_opener_policy
+                .or(other.cross_origin_opener_policy),
+            cross_origin_resource_policy: self
+                .cross_origin_resource_policy
+                .or(other.cross_origin_resource_policy),
             config_file: self.config_file,
             verbosity: self.verbosity,
             silent: self.silent,
@@ -125,85 +925,337 @@ impl ConfigBuilder {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The settings the server runs with, serializing it redacts secrets so it
+/// can be shown to an operator
+#[derive(Debug, Clone, Serialize)]
 pub struct Config {
+    #[serde(serialize_with = "serialize_redacted")]
     pub session_key: Vec<u8>,
+    #[serde(serialize_with = "serialize_display")]
     pub oauth_login_url: Uri,
+    #[serde(serialize_with = "serialize_display")]
     pub oauth_token_url: Uri,
     pub oauth_id: String,
+    #[serde(serialize_with = "serialize_redacted")]
     pub oauth_secret: String,
     pub listen_ip: IpAddr,
     pub listen_port: u16,
+    pub listen: Vec<ListenAddr>,
+    #[serde(serialize_with = "serialize_redacted_uri")]
     pub redis_url: Uri,
+    #[serde(serialize_with = "serialize_display")]
     pub base_url: Uri,
     pub silent: bool,
     pub verbosity: u8,
     pub asset_dir: String,
+    pub raw_html_roles: Vec<String>,
+    pub render_cache_size: usize,
+    pub render_cache_redis: bool,
+    pub render_math: bool,
+    pub compression: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub redirect_port: Option<u16>,
+    pub keep_alive: bool,
+    pub keep_alive_interval: u64,
+    pub header_read_timeout: u64,
+    pub max_concurrent_streams: u32,
+    pub drain_timeout: u64,
+    pub metrics_port: Option<u16>,
+    pub log_format: LogFormat,
+    pub log_filter: Option<String>,
+    #[serde(serialize_with = "serialize_uri")]
+    pub otlp_endpoint: Option<Uri>,
+    pub otlp_sample_ratio: f64,
+    pub rate_limit: bool,
+    pub rate_limit_auth: RateBudget,
+    pub rate_limit_session: RateBudget,
+    pub rate_limit_api_write: RateBudget,
+    pub rate_limit_csp_report: RateBudget,
+    pub trusted_proxies: Vec<IpNet>,
+    pub csp_frame_src: Vec<String>,
+    pub csp_img_src: Vec<String>,
+    pub csp_report: bool,
+    pub csp_report_only: bool,
+    pub security_headers: bool,
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub cross_origin_opener_policy: String,
+    pub cross_origin_resource_policy: String,
 }
 
 impl Config {
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
     pub fn load() -> Config {
-        ConfigBuilder::from_args();
         let settings = ConfigBuilder::from_args();
 
-        match settings.cmd {
-            Some(Subcommand::GenerateConfig) => {
-                let default = ConfigBuilder {
-                    session_key: vec![0, 1, 2, 3, 4, 5].into(),
-                    base_url: Uri::from_static("http://example.com").into(),
-                    oauth_login_url: Uri::from_static("http://example.com").into(),
-                    oauth_token_url: Uri::from_static("http://example.com").into(),
-                    oauth_id: Some("oauth_id".into()),
-                    oauth_secret: Some("oauth_secret".into()),
-                    listen_ip: Some([0, 0, 0, 0].into()),
-                    listen_port: 80.into(),
-                    redis_url: Uri::from_static("redis://server:port/db").into(),
-                    asset_dir: Some("public".into()),
-                    ..Default::default()
-                };
-
-                let settings = toml::to_string_pretty(&default).unwrap_or_else(|e| {
-                    config_err(
-                        format!("Unable to generate sample config: {:?}", e),
-                        clap::ErrorKind::Io,
-                    )
-                });
+        let check = match settings.cmd {
+            Some(Subcommand::Config { cmd: None }) => {
+                let settings =
+                    toml::to_string_pretty(&ConfigBuilder::sample()).unwrap_or_else(|e| {
+                        config_err(
+                            format!("Unable to generate sample config: {:?}", e),
+                            clap::ErrorKind::Io,
+                        )
+                    });
 
                 println!("{}", settings);
                 std::process::exit(0)
             }
-            _ => (),
-        }
+            Some(Subcommand::Config {
+                cmd: Some(ConfigCommand::Check),
+            }) => true,
+            None => false,
+        };
 
-        let mut config_file = String::new();
-        let mut f = File::open(&settings.config_file).unwrap_or_else(|e| {
-            config_err(
-                format!("Unable to open config file: {:?}", e),
-                clap::ErrorKind::Io,
-            )
-        });
-        f.read_to_string(&mut config_file).unwrap_or_else(|e| {
+        let config = Config::resolve(settings).unwrap_or_else(|errors| {
+            let errors: Vec<_> = errors.iter().map(|e| format!("  {}", e)).collect();
             config_err(
-                format!("Unable to read config file: {:?}", e),
-                clap::ErrorKind::Io,
+                format!("Invalid config:\n{}", errors.join("\n")),
+                clap::ErrorKind::InvalidValue,
             )
         });
-        let config_file_settings: ConfigBuilder =
-            toml::from_str(&config_file).unwrap_or_else(|e| {
+
+        if check {
+            let config = toml::to_string_pretty(&config).unwrap_or_else(|e| {
                 config_err(
-                    format!("Unable to load config file: {:?}", e),
+                    format!("Unable to display config: {:?}", e),
                     clap::ErrorKind::Io,
                 )
             });
-        let settings = settings.merge(config_file_settings);
 
-        settings.build().unwrap_or_else(|e| {
-            config_err(
-                format!("Missing required value: {}", e),
-                clap::ErrorKind::MissingRequiredArgument,
-            )
-        })
+            println!("{}", config);
+            std::process::exit(0)
+        }
+
+        config
+    }
+
+    /// Loads the config again while the server is running, the command line
+    /// is unchanged but the environment's secret files and the config file
+    /// may have been updated
+    pub fn reload() -> Result<Config, Vec<String>> {
+        Config::resolve(ConfigBuilder::from_args())
+            .map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    /// Takes the settings that can change on a running server from `new`
+    /// and keeps the rest
+    pub fn reload_from(&self, new: Config) -> Reload {
+        let config = Config {
+            log_filter: new.log_filter.clone(),
+            oauth_login_url: new.oauth_login_url.clone(),
+            oauth_token_url: new.oauth_token_url.clone(),
+            oauth_id: new.oauth_id.clone(),
+            oauth_secret: new.oauth_secret.clone(),
+            rate_limit: new.rate_limit,
+            rate_limit_auth: new.rate_limit_auth,
+            rate_limit_session: new.rate_limit_session,
+            rate_limit_api_write: new.rate_limit_api_write,
+            rate_limit_csp_report: new.rate_limit_csp_report,
+            asset_dir: new.asset_dir.clone(),
+            csp_frame_src: new.csp_frame_src.clone(),
+            csp_img_src: new.csp_img_src.clone(),
+            csp_report: new.csp_report,
+            csp_report_only: new.csp_report_only,
+            security_headers: new.security_headers,
+            hsts_max_age: new.hsts_max_age,
+            hsts_include_subdomains: new.hsts_include_subdomains,
+            referrer_policy: new.referrer_policy.clone(),
+            permissions_policy: new.permissions_policy.clone(),
+            cross_origin_opener_policy: new.cross_origin_opener_policy.clone(),
+            cross_origin_resource_policy: new.cross_origin_resource_policy.clone(),
+            ..self.clone()
+        };
+
+        Reload {
+            changed: changed_settings(self, &config),
+            restart_required: changed_settings(&config, &new),
+            config,
+        }
     }
+
+    /// Combines the command line with the environment and config file, then
+    /// checks the result. Every problem found is returned, each pointing at
+    /// the source of the offending value.
+    fn resolve(settings: ConfigBuilder) -> Result<Config, Vec<ConfigError>> {
+        let env = ConfigBuilder::from_env(std::env::vars());
+        let file = ConfigBuilder::from_file(settings.config_file.as_ref());
+
+        // Collected lowest precedence first so higher sources overwrite
+        let sources: HashMap<_, _> = file
+            .sources
+            .into_iter()
+            .chain(env.sources)
+            .chain(settings.keys().into_iter().map(|key| (key, Source::Cli)))
+            .collect();
+
+        let mut errors = env.errors;
+        errors.extend(file.errors);
+
+        let settings = settings.merge(env.settings).merge(file.settings);
+        errors.extend(settings.validate());
+        let config = settings.build();
+        if let Err(build_errors) = &config {
+            errors.extend(build_errors.iter().cloned());
+        }
+
+        if errors.is_empty() {
+            return config;
+        }
+
+        for error in errors.iter_mut() {
+            if error.source.is_none() {
+                error.source = error.key.as_ref().and_then(|k| sources.get(k).cloned());
+            }
+        }
+
+        Err(errors)
+    }
+}
+
+/// The outcome of reloading a running server's config
+pub struct Reload {
+    pub config: Config,
+    /// Settings that took effect
+    pub changed: Vec<String>,
+    /// Settings that differ from the running server but need a restart
+    pub restart_required: Vec<String>,
+}
+
+/// The names of settings that differ, secrets and the redis url, which may
+/// hold a password, are compared directly as they serialize redacted
+fn changed_settings(a: &Config, b: &Config) -> Vec<String> {
+    let table = |config: &Config| match toml::Value::try_from(config) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => toml::value::Table::new(),
+    };
+    let (a_table, b_table) = (table(a), table(b));
+
+    let mut changed: Vec<_> = a_table
+        .keys()
+        .chain(b_table.keys())
+        .filter(|key| a_table.get(*key) != b_table.get(*key))
+        .cloned()
+        .collect();
+    if a.session_key != b.session_key {
+        changed.push("session_key".to_string());
+    }
+    if a.oauth_secret != b.oauth_secret {
+        changed.push("oauth_secret".to_string());
+    }
+    if a.redis_url != b.redis_url {
+        changed.push("redis_url".to_string());
+    }
+
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+/// Where a setting came from, so a bad value can be tracked down
+#[derive(Debug, Clone)]
+enum Source {
+    Cli,
+    Env(String),
+    File(PathBuf, Option<usize>),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Cli => write!(f, "command line"),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::File(path, Some(line)) => write!(f, "{}:{}", path.display(), line),
+            Source::File(path, None) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A problem with a single setting, or with a source as a whole when there
+/// is no key
+#[derive(Debug, Clone)]
+struct ConfigError {
+    key: Option<String>,
+    message: String,
+    source: Option<Source>,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: Some(key.into()),
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    fn missing(key: &str) -> ConfigError {
+        ConfigError::new(
+            key,
+            format!(
+                "missing required value, set it on the command line, with {}{} or in the config file",
+                ENV_PREFIX,
+                key.to_uppercase()
+            ),
+        )
+    }
+
+    fn with_source(self, source: Source) -> ConfigError {
+        ConfigError {
+            source: Some(source),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            write!(f, "{}: ", source)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The settings read from one source along with where each came from
+#[derive(Default)]
+struct Layer {
+    settings: ConfigBuilder,
+    sources: Vec<(String, Source)>,
+    errors: Vec<ConfigError>,
+}
+
+/// The line a top level key is set on, counting from 1
+fn line_of(config_file: &str, key: &str) -> Option<usize> {
+    config_file
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .or_else(|| line.trim_start().strip_prefix(&format!("\"{}\"", key)))
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|line| line + 1)
+}
+
+fn default_csp_frame_src() -> Vec<String> {
+    vec![
+        "https://www.youtube.com".to_string(),
+        "https://player.vimeo.com".to_string(),
+    ]
+}
+
+fn default_csp_img_src() -> Vec<String> {
+    vec!["https://img.youtube.com".to_string()]
 }
 
 fn config_err(msg: impl AsRef<str>, error: structopt::clap::ErrorKind) -> ! {
@@ -235,6 +1287,52 @@ fn deserialize_base64<'de, D: Deserializer<'de>>(
     }
 }
 
+/// Accepts bare addresses as well as CIDR ranges
+fn parse_net(src: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    src.parse::<IpNet>()
+        .or_else(|e| src.parse::<IpAddr>().map(IpNet::from).map_err(|_| e))
+}
+
+fn deserialize_nets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<IpNet>>, D::Error> {
+    let s = Option::<Vec<String>>::deserialize(deserializer)?;
+    match s {
+        Some(s) => s
+            .iter()
+            .map(|s| parse_net(s).map_err(de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+fn serialize_display<S: Serializer, T: std::fmt::Display>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_redacted<S: Serializer, T>(_value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/// Hides the password of a connection string, keeping the rest so the
+/// target can still be recognised
+fn serialize_redacted_uri<S: Serializer>(url: &Uri, serializer: S) -> Result<S::Ok, S::Error> {
+    let url = url.to_string();
+    let redacted = url
+        .split_once("://")
+        .and_then(|(scheme, rest)| {
+            let (userinfo, host) = rest.split_once('@')?;
+            let user = userinfo.split(':').next().unwrap_or("");
+            Some(format!("{}://{}:{}@{}", scheme, user, REDACTED, host))
+        })
+        .unwrap_or(url);
+    serializer.serialize_str(&redacted)
+}
+
 fn serialize_uri<S: Serializer>(url: &Option<Uri>, serializer: S) -> Result<S::Ok, S::Error> {
     let s = url.as_ref().map(|u| u.to_string());
     s.serialize(serializer)
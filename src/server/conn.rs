@@ -0,0 +1,367 @@
+use axum::http::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+use super::listener::Listener;
+use super::{shutdown, Config};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to stop accepting after an error such as running out of file
+/// descriptors, retrying at once would only spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Protocol settings shared by every connection on a listener
+#[derive(Debug, Clone)]
+pub struct ConnSettings {
+    keep_alive: bool,
+    keep_alive_interval: Option<Duration>,
+    header_read_timeout: Duration,
+    max_concurrent_streams: u32,
+    drain_timeout: Duration,
+}
+
+impl ConnSettings {
+    pub fn new(config: &Config) -> ConnSettings {
+        ConnSettings {
+            keep_alive: config.keep_alive,
+            keep_alive_interval: Some(config.keep_alive_interval)
+                .filter(|secs| config.keep_alive && *secs > 0)
+                .map(Duration::from_secs),
+            header_read_timeout: Duration::from_secs(config.header_read_timeout),
+            max_concurrent_streams: config.max_concurrent_streams,
+            drain_timeout: Duration::from_secs(config.drain_timeout),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Http1,
+    Http2,
+}
+
+/// Tracks whether the server is still accepting work, flipped once and for
+/// all when a shutdown signal arrives
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    draining: watch::Receiver<bool>,
+}
+
+impl Lifecycle {
+    pub fn start() -> Lifecycle {
+        let (draining_tx, draining) = watch::channel(false);
+
+        tokio::spawn(async move {
+            shutdown().await;
+            tracing::info!("shutdown signal received, draining connections");
+            let _ = draining_tx.send(true);
+        });
+
+        Lifecycle { draining }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    pub async fn draining(&self) {
+        let mut draining = self.draining.clone();
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+}
+
+struct OpenConnection {
+    remote_addr: SocketAddr,
+    opened: Instant,
+    protocol: Option<Protocol>,
+}
+
+type OpenConnections = Arc<Mutex<HashMap<u64, OpenConnection>>>;
+
+/// Removes a connection from the open set however its task ends
+struct ConnectionGuard {
+    id: u64,
+    connections: OpenConnections,
+}
+
+impl ConnectionGuard {
+    fn set_protocol(&self, protocol: Protocol) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(conn) = connections.get_mut(&self.id) {
+            conn.protocol = Some(protocol);
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Accepts connections until every open connection has finished after
+/// shutdown begins, or until the drain timeout aborts the ones left. New
+/// connections are still served while draining so readiness probes are
+/// answered with the failure the proxy is waiting for.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    settings: ConnSettings,
+    lifecycle: Lifecycle,
+) {
+    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let connections = OpenConnections::default();
+    let mut tasks = JoinSet::new();
+    let mut next_id = 0;
+
+    let mut draining = false;
+    let drain_timeout = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(drain_timeout);
+
+    loop {
+        let (socket, remote_addr) = tokio::select! {
+            _ = lifecycle.draining(), if !draining => {
+                draining = true;
+                drain_timeout
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + settings.drain_timeout);
+                tracing::info!(
+                    "waiting up to {}s for {} connections to complete",
+                    settings.drain_timeout.as_secs(),
+                    tasks.len()
+                );
+
+                if tasks.is_empty() {
+                    break;
+                }
+                continue;
+            }
+            _ = &mut drain_timeout, if draining => {
+                {
+                    let connections = connections.lock().unwrap_or_else(|e| e.into_inner());
+                    for conn in connections.values() {
+                        tracing::warn!(
+                            remote_addr = %conn.remote_addr,
+                            protocol = ?conn.protocol,
+                            open_secs = conn.opened.elapsed().as_secs(),
+                            "closing connection"
+                        );
+                    }
+                }
+
+                tracing::warn!(
+                    "drain timeout elapsed, closing {} connections",
+                    tasks.len()
+                );
+                tasks.shutdown().await;
+                return;
+            }
+            Some(_) = tasks.join_next() => {
+                if draining && tasks.is_empty() {
+                    break;
+                }
+                continue;
+            }
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    accept_error(err).await;
+                    continue;
+                }
+            },
+        };
+
+        // Building the router's service for a connection cannot fail
+        let Ok(tower_service) = make_service.call(remote_addr).await;
+
+        next_id += 1;
+        connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                next_id,
+                OpenConnection {
+                    remote_addr,
+                    opened: Instant::now(),
+                    protocol: None,
+                },
+            );
+        let guard = ConnectionGuard {
+            id: next_id,
+            connections: connections.clone(),
+        };
+
+        let tls = tls.clone();
+        let settings = settings.clone();
+        let lifecycle = lifecycle.clone();
+
+        tasks.spawn(async move {
+            if let Some(tls) = tls {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(socket)).await {
+                    Ok(Ok(socket)) => {
+                        let protocol = match socket.get_ref().1.alpn_protocol() {
+                            Some(b"h2") => Protocol::Http2,
+                            _ => Protocol::Http1,
+                        };
+                        guard.set_protocol(protocol);
+                        serve_connection(
+                            socket,
+                            Some(protocol),
+                            tower_service,
+                            &settings,
+                            &lifecycle,
+                        )
+                        .await
+                    }
+                    Ok(Err(err)) => tracing::debug!("tls handshake failed: {err}"),
+                    Err(_) => tracing::debug!("tls handshake timed out"),
+                }
+            } else {
+                serve_connection(socket, None, tower_service, &settings, &lifecycle).await
+            }
+
+            drop(guard);
+        });
+    }
+
+    tracing::info!("all connections drained");
+}
+
+/// Errors from a connection that failed before it was accepted only affect
+/// that connection, any other error pauses accepting for a moment
+async fn accept_error(err: std::io::Error) {
+    use std::io::ErrorKind;
+
+    match err.kind() {
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset => {
+            tracing::debug!("accept failed: {}", err)
+        }
+        _ => {
+            tracing::error!(
+                "accept failed, retrying in {}s: {}",
+                ACCEPT_ERROR_DELAY.as_secs(),
+                err
+            );
+            tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+        }
+    }
+}
+
+/// Serves the protocol negotiated with tls alpn. Without one the connection
+/// preface decides, so plaintext clients may use http2 with prior knowledge.
+async fn serve_connection<I, S>(
+    socket: I,
+    protocol: Option<Protocol>,
+    tower_service: S,
+    settings: &ConnSettings,
+    lifecycle: &Lifecycle,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<
+            Request<Incoming>,
+            Response = axum::response::Response,
+            Error = std::convert::Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let socket = TokioIo::new(socket);
+
+    let responded = Arc::new(Notify::new());
+    let hyper_service = {
+        let responded = responded.clone();
+        hyper::service::service_fn(move |request: Request<Incoming>| {
+            let response = tower_service.clone().call(request);
+            let responded = responded.clone();
+            async move {
+                let response = response.await;
+                responded.notify_one();
+                response
+            }
+        })
+    };
+
+    // Http1 connections opened while draining also say they will close
+    let draining = lifecycle.is_draining();
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(settings.keep_alive && !draining)
+        .header_read_timeout(settings.header_read_timeout)
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(settings.max_concurrent_streams)
+        .keep_alive_interval(settings.keep_alive_interval);
+
+    let builder = match protocol {
+        Some(Protocol::Http1) => builder.http1_only(),
+        Some(Protocol::Http2) => builder.http2_only(),
+        None => builder,
+    };
+
+    let conn = builder.serve_connection_with_upgrades(socket, hyper_service);
+    drive(conn, |conn| conn.graceful_shutdown(), lifecycle, &responded).await
+}
+
+/// Runs a connection to completion, asking it to finish its in flight
+/// requests and close once shutdown begins. A connection opened while
+/// draining is shut down once its first response is ready, shutting it
+/// down straight away would close it before the request is read. Either
+/// protocol then closes after finishing the requests it has already started.
+async fn drive<C, E>(
+    conn: C,
+    graceful_shutdown: fn(Pin<&mut C>),
+    lifecycle: &Lifecycle,
+    responded: &Notify,
+) where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    let mut conn = std::pin::pin!(conn);
+
+    let result = if lifecycle.is_draining() {
+        tokio::select! {
+            result = conn.as_mut() => result,
+            _ = responded.notified() => {
+                graceful_shutdown(conn.as_mut());
+                conn.await
+            }
+        }
+    } else {
+        tokio::select! {
+            result = conn.as_mut() => result,
+            _ = lifecycle.draining() => {
+                graceful_shutdown(conn.as_mut());
+                conn.await
+            }
+        }
+    };
+
+    if let Err(err) = result {
+        tracing::error!("failed to serve connection: {err:#}");
+    }
+}
@@ -0,0 +1,175 @@
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::{header, HeaderName, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::forwarded::ClientIp;
+use super::rate_limit::{Group, Key, RateLimiter};
+use super::reload::Reloadable;
+use super::{Config, JsonError};
+
+use std::sync::Arc;
+
+pub const REPORT_PATH: &str = "/csp-report";
+const REPORT_GROUP: &str = "csp-endpoint";
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+/// Longest value logged from a report, anything past it is cut off
+const REPORT_FIELD_LIMIT: usize = 256;
+
+static REPORTING_ENDPOINTS: HeaderName = HeaderName::from_static("reporting-endpoints");
+
+tokio::task_local! {
+    static NONCE: String;
+}
+
+/// The nonce for the page currently being rendered, allowing inline scripts
+/// and styles in templates
+pub fn nonce() -> String {
+    NONCE.try_with(|nonce| nonce.clone()).unwrap_or_default()
+}
+
+/// The Content-Security-Policy sent with every html page, built once from
+/// config with a placeholder for the per request nonce
+pub struct Policy {
+    directives: String,
+    report_only: bool,
+    reporting_endpoints: Option<HeaderValue>,
+    rand: SystemRandom,
+}
+
+impl Policy {
+    pub fn new(config: &Config) -> Policy {
+        let sources = |extra: &[String]| {
+            std::iter::once("'self'")
+                .chain(extra.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let frame_src = if config.csp_frame_src.is_empty() {
+            "'none'".to_string()
+        } else {
+            config.csp_frame_src.join(" ")
+        };
+
+        let mut directives = vec![
+            "default-src 'none'".to_string(),
+            "connect-src 'self'".to_string(),
+            "font-src 'self'".to_string(),
+            format!("frame-src {}", frame_src),
+            format!("img-src {}", sources(&config.csp_img_src)),
+            "media-src 'self'".to_string(),
+            format!("script-src 'self' 'nonce-{NONCE_PLACEHOLDER}' 'wasm-unsafe-eval'"),
+            format!("style-src 'self' 'nonce-{NONCE_PLACEHOLDER}'"),
+            "frame-ancestors 'none'".to_string(),
+            "base-uri 'none'".to_string(),
+            "form-action 'self'".to_string(),
+        ];
+        if config.csp_report {
+            directives.push(format!("report-uri {}", REPORT_PATH));
+            directives.push(format!("report-to {}", REPORT_GROUP));
+        }
+
+        Policy {
+            directives: directives.join("; ") + ";",
+            report_only: config.csp_report_only,
+            reporting_endpoints: config.csp_report.then(|| {
+                HeaderValue::from_str(&format!("{}=\"{}\"", REPORT_GROUP, REPORT_PATH))
+                    .expect("Valid reporting endpoint")
+            }),
+            rand: SystemRandom::new(),
+        }
+    }
+
+    fn create_nonce(&self) -> String {
+        let mut nonce = [0; 16];
+        self.rand
+            .fill(&mut nonce)
+            .expect("Crypto error, could not fill csp nonce");
+        base64::encode(nonce)
+    }
+
+    fn header_name(&self) -> HeaderName {
+        if self.report_only {
+            header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+        } else {
+            header::CONTENT_SECURITY_POLICY
+        }
+    }
+}
+
+/// Generates a nonce for the request, makes it available while the page
+/// renders and sends the matching policy
+pub async fn apply(
+    State(policy): State<Reloadable<Policy>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let policy = policy.current();
+    let nonce = policy.create_nonce();
+    let mut res = NONCE.scope(nonce.clone(), next.run(req)).await;
+
+    // A 304 refreshes the headers the browser has cached alongside the body,
+    // a new nonce there would no longer match the cached page
+    if res.status() == StatusCode::NOT_MODIFIED {
+        return res;
+    }
+
+    let directives = policy.directives.replace(NONCE_PLACEHOLDER, &nonce);
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&directives) {
+        headers.insert(policy.header_name(), value);
+    }
+    if let Some(endpoints) = policy.reporting_endpoints.clone() {
+        headers.insert(REPORTING_ENDPOINTS.clone(), endpoints);
+    }
+
+    res
+}
+
+/// Logs violations sent either as a legacy `application/csp-report` body or
+/// a Reporting API `application/reports+json` batch. Anyone can post here,
+/// so reports are rate limited per ip and logged fields are truncated.
+pub async fn report(
+    ClientIp(ip): ClientIp,
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    body: Bytes,
+) -> Result<StatusCode, JsonError> {
+    rate_limiter.check(Group::CspReport, &[Key::Ip(ip)]).await?;
+
+    let reports = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Array(reports)) => reports
+            .into_iter()
+            .filter(|r| r["type"] == "csp-violation")
+            .map(|mut r| r["body"].take())
+            .collect(),
+        Ok(mut report) => vec![report["csp-report"].take()],
+        Err(err) => {
+            tracing::debug!("malformed csp report: {}", err);
+            return Ok(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    for report in reports {
+        let field = |legacy: &str, modern: &str| {
+            report[legacy]
+                .as_str()
+                .or_else(|| report[modern].as_str())
+                .unwrap_or("")
+                .chars()
+                .take(REPORT_FIELD_LIMIT)
+                .collect::<String>()
+        };
+
+        tracing::warn!(
+            document = field("document-uri", "documentURL"),
+            directive = field("effective-directive", "effectiveDirective"),
+            blocked = field("blocked-uri", "blockedURL"),
+            disposition = field("disposition", "disposition"),
+            "csp violation"
+        );
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -2,6 +2,7 @@ pub use deadpool_redis::Connection;
 
 use std::sync::Arc;
 
+use super::metrics::metrics;
 use super::Error;
 
 #[derive(Clone)]
@@ -20,6 +21,15 @@ impl Db {
 
     #[tracing::instrument(name = "db::get", skip_all, err)]
     pub async fn get(&self) -> Result<Connection, Error> {
+        let _timer = metrics().redis_pool_wait.start_timer();
         Ok(self.pool.get().await?)
     }
+
+    pub fn record_pool_metrics(&self) {
+        let status = self.pool.status();
+        let metrics = metrics();
+        metrics.redis_pool_size.set(status.size as i64);
+        metrics.redis_pool_available.set(status.available as i64);
+        metrics.redis_pool_max_size.set(status.max_size as i64);
+    }
 }
@@ -14,6 +14,7 @@ pub enum Error {
     Timeout(tokio::time::error::Elapsed),
     Pool(deadpool_redis::PoolError),
     CreatePool(deadpool_redis::CreatePoolError),
+    RateLimited(std::time::Duration),
 }
 
 #[derive(Debug)]
@@ -27,6 +28,8 @@ impl Error {
         JsonError {
             code: self.status_code(),
             message: self.to_string(),
+            request_id: super::request_id::current().map(|id| id.to_string()),
+            retry_after: self.retry_after(),
         }
     }
 
@@ -35,10 +38,19 @@ impl Error {
             Error::NotFound => 404,
             Error::ResourceNotFound(_) => 404,
             Error::Unauthorized => 401,
+            Error::RateLimited(_) => 429,
             _ => 500,
         }
     }
 
+    /// Whole seconds a client should wait before retrying, if it should
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::RateLimited(wait) => Some(wait.as_millis().div_ceil(1000).max(1) as u64),
+            _ => None,
+        }
+    }
+
     pub fn status(&self) -> axum::http::StatusCode {
         axum::http::StatusCode::from_u16(self.status_code()).unwrap()
     }
@@ -85,23 +97,37 @@ impl fmt::Display for Error {
         match self {
             Error::Redis(redis) => write!(f, "Redis: {}", redis),
             Error::Reqwest(reqwest) => write!(f, "Reqwest: {}", reqwest),
-            Error::ResourceNotFound(res) => write!(f, "Unable to find: {:?}", res),
+            Error::ResourceNotFound(res) => write!(f, "Unable to find: {}", res),
             Error::Unauthorized => write!(f, "Unauthorized"),
             Error::Render((name, err)) => write!(f, "Failed to render {} {}", name, err),
             Error::NotFound => write!(f, "Not found"),
             Error::Timeout(timeout) => write!(f, "Timeout: {}", timeout),
             Error::CreatePool(err) => write!(f, "Create Pool: {}", err),
             Error::Pool(err) => write!(f, "Pool: {}", err),
+            Error::RateLimited(_) => write!(f, "Too many requests"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::User(id) => write!(f, "User({})", id),
+            Resource::Post(id) => write!(f, "Post({})", id),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonError {
     pub code: u16,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
 }
 
 impl From<Error> for JsonError {
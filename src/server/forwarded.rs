@@ -0,0 +1,279 @@
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Extension, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::{async_trait, RequestPartsExt};
+use ipnet::IpNet;
+
+use super::Config;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// The address of the visitor, looking through any trusted proxies
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// The scheme the visitor used to reach the outermost trusted proxy, only
+/// present when the request passed through one
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardedProto(pub Option<Proto>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proto {
+    Http,
+    Https,
+}
+
+impl Proto {
+    fn parse(value: &str) -> Option<Proto> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "http" => Some(Proto::Http),
+            "https" => Some(Proto::Https),
+            _ => None,
+        }
+    }
+
+    pub fn scheme(&self) -> http::uri::Scheme {
+        match self {
+            Proto::Http => http::uri::Scheme::HTTP,
+            Proto::Https => http::uri::Scheme::HTTPS,
+        }
+    }
+}
+
+/// A hop taken from a forwarding header
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<Proto>,
+}
+
+/// Resolves the real client of every request before anything else sees it,
+/// forwarding headers are only believed when the peer is a trusted proxy
+pub async fn resolve(
+    State(config): State<Arc<Config>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let (client, proto) = client(&config.trusted_proxies, peer.ip(), req.headers());
+
+    req.extensions_mut().insert(ClientIp(client));
+    req.extensions_mut().insert(ForwardedProto(proto));
+
+    next.run(req).await
+}
+
+fn client(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> (IpAddr, Option<Proto>) {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return (peer, None);
+    }
+
+    let hops = hops(headers);
+    // Set by the nearest proxy, any entries to its left could be forged
+    let forwarded_proto = values(headers, X_FORWARDED_PROTO)
+        .last()
+        .and_then(|v| Proto::parse(v));
+
+    // Walk back from the nearest hop, the first untrusted address is the
+    // furthest we can believe, everything to its left could be forged
+    let mut client = (peer, None);
+    for hop in hops.iter().rev() {
+        match hop.addr {
+            Some(addr) => {
+                client = (addr, hop.proto);
+                if !is_trusted(&addr) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+
+    let (addr, proto) = client;
+    (addr, proto.or(forwarded_proto))
+}
+
+/// Every comma separated entry of a header, in order across repeated headers
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect()
+}
+
+/// Collects hops from the standard `Forwarded` header, falling back to the
+/// de facto `X-Forwarded-For` and `X-Real-IP` headers
+fn hops(headers: &HeaderMap) -> Vec<Hop> {
+    let forwarded = values(headers, header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                let mut hop = Hop {
+                    addr: None,
+                    proto: None,
+                };
+                for pair in element.split(';') {
+                    match pair.split_once('=') {
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("for") => {
+                            hop.addr = parse_node(value)
+                        }
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("proto") => {
+                            hop.proto = Proto::parse(value.trim().trim_matches('"'))
+                        }
+                        _ => (),
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+
+    let forwarded_for = values(headers, X_FORWARDED_FOR);
+    let forwarded_for = if forwarded_for.is_empty() {
+        values(headers, X_REAL_IP)
+    } else {
+        forwarded_for
+    };
+
+    // Each proxy appends to both headers, so their entries line up from the
+    // right even when the nearest proxies only set one of them
+    let protos = values(headers, X_FORWARDED_PROTO);
+    let offset = forwarded_for.len() as isize - protos.len() as isize;
+
+    forwarded_for
+        .into_iter()
+        .enumerate()
+        .map(|(idx, node)| Hop {
+            addr: parse_node(node),
+            proto: usize::try_from(idx as isize - offset)
+                .ok()
+                .and_then(|idx| protos.get(idx))
+                .and_then(|proto| Proto::parse(proto)),
+        })
+        .collect()
+}
+
+/// Parses a node which may be quoted, bracketed or carry a port, obfuscated
+/// and unknown nodes yield nothing
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Some(rest) = node.strip_prefix('[') {
+        let (addr, _port) = rest.split_once(']')?;
+        return addr.parse().ok();
+    }
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for ClientIp {
+    type Rejection = axum::extract::rejection::ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(client) = parts.extract::<Extension<ClientIp>>().await?;
+
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for ForwardedProto {
+    type Rejection = axum::extract::rejection::ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(proto) = parts.extract::<Extension<ForwardedProto>>().await?;
+
+        Ok(proto)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const PEER: &str = "10.0.0.1";
+
+    fn resolve(headers: &[(&str, &str)]) -> (IpAddr, Option<Proto>) {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap();
+            map.append(name, HeaderValue::from_str(value).unwrap());
+        }
+
+        client(&trusted, PEER.parse().unwrap(), &map)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("1.1.1.1"));
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+
+        let client = client(&trusted, ip("2.2.2.2"), &headers);
+        assert_eq!(client, (ip("2.2.2.2"), None));
+    }
+
+    #[test]
+    fn ignores_forged_forwarded_for() {
+        let client = resolve(&[(X_FORWARDED_FOR, "1.1.1.1, 2.2.2.2")]);
+        assert_eq!(client, (ip("2.2.2.2"), None));
+
+        let client = resolve(&[
+            (X_FORWARDED_FOR, "1.1.1.1"),
+            (X_FORWARDED_FOR, "2.2.2.2, 10.0.0.2"),
+        ]);
+        assert_eq!(client, (ip("2.2.2.2"), None));
+    }
+
+    #[test]
+    fn ignores_forged_forwarded_proto() {
+        let client = resolve(&[
+            (X_FORWARDED_FOR, "1.1.1.1, 2.2.2.2"),
+            (X_FORWARDED_PROTO, "https, http"),
+        ]);
+        assert_eq!(client, (ip("2.2.2.2"), Some(Proto::Http)));
+
+        let client = resolve(&[
+            (X_FORWARDED_FOR, "2.2.2.2, 10.0.0.2"),
+            (X_FORWARDED_PROTO, "https"),
+        ]);
+        assert_eq!(client, (ip("2.2.2.2"), Some(Proto::Https)));
+    }
+
+    #[test]
+    fn ignores_forged_forwarded() {
+        let client = resolve(&[(
+            header::FORWARDED.as_str(),
+            "for=1.1.1.1;proto=http, for=\"[2001:db8::1]:443\";proto=https",
+        )]);
+        assert_eq!(client, (ip("2001:db8::1"), Some(Proto::Https)));
+
+        let client = resolve(&[(
+            header::FORWARDED.as_str(),
+            "for=1.1.1.1;proto=https, for=unknown, for=10.0.0.2;proto=http",
+        )]);
+        assert_eq!(client, (ip("10.0.0.2"), Some(Proto::Http)));
+    }
+}
@@ -0,0 +1,189 @@
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use super::config::ListenAddr;
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The first file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Unix socket peers have no address of their own, they are reported as
+/// loopback so a local reverse proxy can be listed in trusted_proxies
+const UNIX_PEER_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+/// A socket accepting website connections
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again once the listener closes, sockets passed by
+    /// systemd have none as systemd owns them
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Binds every configured address, a path left behind by a previous run
+    /// is replaced
+    pub async fn bind(addrs: &[ListenAddr]) -> io::Result<Vec<Listener>> {
+        let mut listeners = Vec::new();
+
+        for addr in addrs {
+            match addr {
+                ListenAddr::Tcp(addr) => {
+                    listeners.push(Listener::Tcp(TcpListener::bind(addr).await?))
+                }
+                ListenAddr::Unix(path) => {
+                    remove_stale_socket(path)?;
+                    let listener = UnixListener::bind(path)?;
+                    listeners.push(Listener::Unix(listener, Some(path.clone())));
+                }
+                ListenAddr::Systemd => listeners.extend(systemd_listeners()?),
+            }
+        }
+
+        Ok(listeners)
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, remote_addr) = listener.accept().await?;
+                Ok((Stream::Tcp(socket), remote_addr))
+            }
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Stream::Unix(socket), UNIX_PEER_ADDR))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(listener, _) => {
+                match listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(Path::to_owned))
+                {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "unix"),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes the listening sockets passed by systemd, following the
+/// sd_listen_fds protocol. The variables are only honoured when LISTEN_PID
+/// names this process so children never claim them.
+fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "LISTEN_PID does not match this process, no sockets were passed by systemd",
+        ));
+    }
+    let count = var("LISTEN_FDS").unwrap_or(0) as RawFd;
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Safety: systemd hands these descriptors to this process to own,
+            // LISTEN_PID was checked above and each is only taken once
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let unix = std::os::unix::net::UnixListener::from(fd);
+
+            // Only a unix socket has a unix local address
+            let listener = if unix.local_addr().is_ok() {
+                unix.set_nonblocking(true)?;
+                Listener::Unix(UnixListener::from_std(unix)?, None)
+            } else {
+                let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+                tcp.set_nonblocking(true)?;
+                Listener::Tcp(TcpListener::from_std(tcp)?)
+            };
+
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// An accepted connection from any kind of listener
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(socket) => Pin::new(socket).poll_read(cx, buf),
+            Stream::Unix(socket) => Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(socket) => Pin::new(socket).poll_write(cx, buf),
+            Stream::Unix(socket) => Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(socket) => Pin::new(socket).poll_flush(cx),
+            Stream::Unix(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(socket) => Pin::new(socket).poll_shutdown(cx),
+            Stream::Unix(socket) => Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,1091 @@
+use latex2mathml::DisplayStyle;
+use pulldown_cmark::{CowStr, Event, HeadingLevel, LinkType, Options, Parser, Tag, TagEnd};
+use pulldown_cmark_escape::{escape_href, escape_html};
+
+use lru::LruCache;
+
+use super::db::Db;
+use super::metrics::metrics;
+use super::posts::Post;
+use super::shortcodes::{self, Args, ShortcodeError};
+use super::users::{User, ADMIN_ROLE};
+use super::Error;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, OnceLock};
+
+const LEGACY_YOUTUBE: &str = "<youtube:";
+/// Starts every heading and footnote id so they never collide with the ids
+/// of the page around the post
+const ID_PREFIX: &str = "content-";
+const FOOTNOTE_ID_PREFIX: &str = "content-fn-";
+
+type Spanned<'a> = (Event<'a>, Range<usize>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtmlPolicy {
+    /// Raw html written by the author is passed through untouched
+    Trusted,
+    /// Output is restricted to an allowlist of elements and attributes
+    Restricted,
+}
+
+/// Whether a malformed shortcode is shown with its error and source, or left
+/// out so readers never see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortcodeErrors {
+    Shown,
+    Hidden,
+}
+
+/// Bump whenever a change to the renderer alters its output so cached html
+/// from older versions is no longer served
+pub const RENDERER_VERSION: &str = "7";
+
+const RENDER_CACHE_TTL: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Clone)]
+struct CachedRender {
+    digest: String,
+    html: Arc<str>,
+}
+
+pub struct Renderer {
+    raw_html_roles: Vec<String>,
+    math: bool,
+    cache: Option<Mutex<LruCache<u64, CachedRender>>>,
+    redis: Option<Db>,
+}
+
+impl Renderer {
+    pub fn new(
+        raw_html_roles: Vec<String>,
+        math: bool,
+        cache_size: usize,
+        redis: Option<Db>,
+    ) -> Renderer {
+        let cache = NonZeroUsize::new(cache_size).map(|size| Mutex::new(LruCache::new(size)));
+        Renderer {
+            raw_html_roles,
+            math,
+            cache,
+            redis,
+        }
+    }
+
+    pub fn policy(&self, post: &Post) -> HtmlPolicy {
+        match post.author_role {
+            Some(ref role) if self.raw_html_roles.contains(role) => HtmlPolicy::Trusted,
+            _ => HtmlPolicy::Restricted,
+        }
+    }
+
+    /// Authors and admins see the shortcode errors in a post so they can be
+    /// fixed, everyone else gets the post without them
+    pub fn shortcode_errors(&self, viewer: Option<&User>, post: &Post) -> ShortcodeErrors {
+        match viewer {
+            Some(user) if user.id == post.author_id || user.role == ADMIN_ROLE => {
+                ShortcodeErrors::Shown
+            }
+            _ => ShortcodeErrors::Hidden,
+        }
+    }
+
+    fn render_post(&self, post: &Post, errors: ShortcodeErrors) -> Arc<str> {
+        let timer = metrics().markdown_render_duration.start_timer();
+        let html = render(&post.content, options(self.math), self.policy(post), errors).into();
+        timer.observe_duration();
+        html
+    }
+
+    fn digest(&self, post: &Post) -> String {
+        let policy: &[u8] = match self.policy(post) {
+            HtmlPolicy::Trusted => b"trusted",
+            HtmlPolicy::Restricted => b"restricted",
+        };
+
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        context.update(RENDERER_VERSION.as_bytes());
+        context.update(policy);
+        context.update(if self.math { b"math" } else { b"text" });
+        context.update(post.content.as_bytes());
+        base64::encode(context.finish())
+    }
+
+    /// Renders each post's content, reusing html cached in process or in
+    /// redis when the post's digest is unchanged. Only renders without
+    /// shortcode errors are cached, the viewer's own are always fresh.
+    #[tracing::instrument(name = "markdown::render_all", skip_all)]
+    pub async fn render_all(&self, posts: &[Post], viewer: Option<&User>) -> Vec<Arc<str>> {
+        let digests: Vec<_> = posts.iter().map(|p| self.digest(p)).collect();
+        let mut rendered: Vec<Option<Arc<str>>> = posts
+            .iter()
+            .map(|post| match self.shortcode_errors(viewer, post) {
+                ShortcodeErrors::Shown => Some(self.render_post(post, ShortcodeErrors::Shown)),
+                ShortcodeErrors::Hidden => None,
+            })
+            .collect();
+
+        if let Some(cache) = self.cache.as_ref() {
+            let mut cache = cache.lock().unwrap();
+            for (idx, post) in posts.iter().enumerate() {
+                if rendered[idx].is_some() {
+                    continue;
+                }
+                rendered[idx] = cache
+                    .get(&post.id)
+                    .filter(|c| c.digest == digests[idx])
+                    .map(|c| c.html.clone());
+            }
+        }
+
+        let misses: Vec<_> = (0..posts.len())
+            .filter(|&i| rendered[i].is_none())
+            .collect();
+        if misses.is_empty() {
+            return rendered.into_iter().flatten().collect();
+        }
+
+        let mut stored = Vec::new();
+        if let Some(db) = self.redis.as_ref() {
+            let ids: Vec<_> = misses.iter().map(|&i| posts[i].id).collect();
+            match load_cached(db, &ids).await {
+                Ok(cached) => {
+                    for (&idx, cached) in misses.iter().zip(cached) {
+                        if let Some(cached) = cached.filter(|c| c.digest == digests[idx]) {
+                            rendered[idx] = Some(cached.html.clone());
+                            stored.push((posts[idx].id, cached));
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("unable to load cached renders: {}", e),
+            }
+        }
+
+        let mut fresh = Vec::new();
+        for idx in misses {
+            if rendered[idx].is_some() {
+                continue;
+            }
+
+            let post = &posts[idx];
+            let html = self.render_post(post, ShortcodeErrors::Hidden);
+            rendered[idx] = Some(html.clone());
+            fresh.push((
+                post.id,
+                CachedRender {
+                    digest: digests[idx].clone(),
+                    html,
+                },
+            ));
+        }
+
+        if let Some(db) = self.redis.as_ref().filter(|_| !fresh.is_empty()) {
+            if let Err(e) = store_cached(db, &fresh).await {
+                tracing::warn!("unable to store cached renders: {}", e);
+            }
+        }
+
+        if let Some(cache) = self.cache.as_ref() {
+            let mut cache = cache.lock().unwrap();
+            for (id, cached) in stored.into_iter().chain(fresh) {
+                cache.put(id, cached);
+            }
+        }
+
+        rendered.into_iter().flatten().collect()
+    }
+
+    pub async fn render_one(&self, post: &Post, viewer: Option<&User>) -> Arc<str> {
+        self.render_all(std::slice::from_ref(post), viewer)
+            .await
+            .pop()
+            .unwrap_or_else(|| Arc::from(""))
+    }
+
+    /// Drops any cached html for a post after it has been edited
+    pub async fn invalidate(&self, id: u64) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().unwrap().pop(&id);
+        }
+
+        if let Some(db) = self.redis.as_ref() {
+            let res: Result<(), Error> = async {
+                let mut db = db.get().await?;
+                let _: () = redis::cmd("del")
+                    .arg(format!("renderedPost:{}", id))
+                    .query_async(&mut db)
+                    .await?;
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = res {
+                tracing::warn!("unable to invalidate cached render: {}", e);
+            }
+        }
+    }
+}
+
+async fn load_cached(db: &Db, ids: &[u64]) -> Result<Vec<Option<CachedRender>>, Error> {
+    let mut db = db.get().await?;
+    let mut pipe = redis::Pipeline::with_capacity(ids.len());
+    for id in ids {
+        pipe.cmd("hmget")
+            .arg(format!("renderedPost:{}", id))
+            .arg("digest")
+            .arg("html");
+    }
+
+    let cached: Vec<(Option<String>, Option<String>)> = pipe.query_async(&mut db).await?;
+
+    Ok(cached
+        .into_iter()
+        .map(|cached| match cached {
+            (Some(digest), Some(html)) => Some(CachedRender {
+                digest,
+                html: html.into(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+async fn store_cached(db: &Db, renders: &[(u64, CachedRender)]) -> Result<(), Error> {
+    let mut db = db.get().await?;
+    let mut pipe = redis::Pipeline::with_capacity(renders.len() * 2);
+    for (id, cached) in renders {
+        let key = format!("renderedPost:{}", id);
+        pipe.hset_multiple(
+            key.as_str(),
+            &[("digest", cached.digest.as_ref()), ("html", &*cached.html)],
+        )
+        .ignore();
+        pipe.expire(key.as_str(), RENDER_CACHE_TTL as usize)
+            .ignore();
+    }
+
+    let _: () = pipe.query_async(&mut db).await?;
+    Ok(())
+}
+
+pub fn render(
+    content: &str,
+    options: Options,
+    policy: HtmlPolicy,
+    errors: ShortcodeErrors,
+) -> String {
+    let content = isolate_shortcodes(content, options);
+    let events = Parser::new_ext(&content, options).into_offset_iter();
+    let events = prefix_footnotes(events);
+    let (events, headings) = anchor_headings(events);
+    let events = expand_shortcodes(&content, &events, &headings, errors);
+    let output = push_html(events);
+
+    match policy {
+        HtmlPolicy::Trusted => output,
+        HtmlPolicy::Restricted => sanitizer().clean(&output).to_string(),
+    }
+}
+
+const VIMEO_PLAYER: &str = "https://player.vimeo.com/video/";
+
+#[rustfmt::skip]
+const MATHML_TAGS: &[&str] = &[
+    "math", "mi", "mn", "mo", "ms", "mspace", "mtext", "mrow", "mfrac", "msqrt",
+    "mroot", "msub", "msup", "msubsup", "munder", "mover", "munderover", "mtable",
+    "mtr", "mtd", "mstyle", "mpadded", "mphantom", "menclose", "semantics",
+];
+
+#[rustfmt::skip]
+const MATHML_ATTRIBUTES: &[&str] = &[
+    "display", "mathvariant", "stretchy", "fence", "separator", "lspace", "rspace",
+    "linethickness", "columnalign", "accent", "accentunder", "movablelimits",
+    "displaystyle", "scriptlevel", "notation", "largeop", "symmetric", "width",
+];
+
+fn mathml_sanitizer() -> &'static ammonia::Builder<'static> {
+    static SANITIZER: OnceLock<ammonia::Builder<'static>> = OnceLock::new();
+
+    SANITIZER.get_or_init(|| {
+        let mut builder = ammonia::Builder::empty();
+        builder
+            .add_tags(MATHML_TAGS)
+            .add_tag_attributes("math", ["xmlns"]);
+
+        for tag in MATHML_TAGS {
+            builder.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+        }
+
+        builder
+    })
+}
+
+#[rustfmt::skip]
+const RENDERER_CLASSES: &[(&str, &[&str])] = &[
+    ("a", &["heading-anchor"]),
+    ("blockquote", &["markdown-alert-note", "markdown-alert-tip", "markdown-alert-important", "markdown-alert-warning", "markdown-alert-caution"]),
+    ("div", &["footnote-definition"]),
+    ("nav", &["toc"]),
+    ("sup", &["footnote-reference", "footnote-definition-label"]),
+];
+
+const HEADING_TAGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// The allowlist applied to posts by authors without a raw html role, it
+/// admits everything the renderer and shortcodes produce themselves. Ids
+/// and classes are limited to the forms generated here so a post cannot
+/// restyle or clobber the rest of the page.
+fn sanitizer() -> &'static ammonia::Builder<'static> {
+    static SANITIZER: OnceLock<ammonia::Builder<'static>> = OnceLock::new();
+
+    SANITIZER.get_or_init(|| {
+        let mut builder = ammonia::Builder::default();
+        builder
+            .add_tags(["input", "iframe", "audio"])
+            .add_tags(MATHML_TAGS)
+            .add_tag_attributes("div", ["id"])
+            // Code blocks carry the language of their fence
+            .add_tag_attributes("code", ["class"])
+            .add_tag_attributes(
+                "a",
+                ["target", "aria-hidden", "data-video-id", "data-start"],
+            )
+            .add_tag_attributes("img", ["loading"])
+            .add_tag_attributes("input", ["type", "checked", "disabled"])
+            .add_tag_attribute_values("input", "type", ["checkbox"])
+            .add_tag_attributes("iframe", ["src", "loading", "allow", "allowfullscreen"])
+            .add_tag_attributes("audio", ["src", "controls", "preload"])
+            .add_tag_attributes("math", ["xmlns"])
+            .attribute_filter(|element, attribute, value| match (element, attribute) {
+                ("iframe", "src") if !value.starts_with(VIMEO_PLAYER) => None,
+                ("div", "id") if !value.starts_with(FOOTNOTE_ID_PREFIX) => None,
+                (_, "id") if !value.starts_with(ID_PREFIX) => None,
+                ("code", "class") => code_class(value).map(Into::into),
+                _ => Some(value.into()),
+            });
+
+        for tag in HEADING_TAGS {
+            builder.add_tag_attributes(tag, ["id"]);
+        }
+        for tag in MATHML_TAGS {
+            builder.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+        }
+        for (tag, classes) in RENDERER_CLASSES.iter().chain(shortcodes::CLASSES) {
+            builder.add_allowed_classes(*tag, classes.iter());
+        }
+
+        builder
+    })
+}
+
+/// Keeps the classes of a code element that the renderer could have written
+fn code_class(value: &str) -> Option<&str> {
+    let valid = value == "math-error"
+        || value.strip_prefix("language-").is_some_and(|language| {
+            !language.is_empty()
+                && language
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c))
+        });
+
+    valid.then_some(value)
+}
+
+/// Surrounds lines consisting of a single shortcode with blank lines so each
+/// one is parsed as its own paragraph. Only top level paragraphs are split,
+/// a blank line inside a list or block quote would change its layout.
+fn isolate_shortcodes(content: &str, options: Options) -> Cow<'_, str> {
+    let mut lines = Vec::new();
+    let mut depth = 0;
+
+    for (event, range) in Parser::new_ext(content, options).into_offset_iter() {
+        match event {
+            Event::Start(tag) => {
+                if depth == 0 && matches!(tag, Tag::Paragraph) {
+                    lines.extend(shortcode_lines(content, range));
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+    }
+
+    if lines.is_empty() {
+        return Cow::Borrowed(content);
+    }
+
+    let mut output = String::with_capacity(content.len() + lines.len() * 2);
+    let mut last = 0;
+    for line in lines {
+        output.push_str(&content[last..line.start]);
+        output.push('\n');
+        output.push_str(&content[line.clone()]);
+        output.push('\n');
+        last = line.end;
+    }
+    output.push_str(&content[last..]);
+
+    Cow::Owned(output)
+}
+
+/// The lines of a paragraph that are a shortcode on their own, a paragraph
+/// that is only a shortcode is already isolated
+fn shortcode_lines(content: &str, paragraph: Range<usize>) -> Vec<Range<usize>> {
+    let source = &content[paragraph.clone()];
+    if shortcodes::parse(source).is_some() {
+        return Vec::new();
+    }
+
+    let mut lines = Vec::new();
+    let mut start = paragraph.start;
+    for line in source.split_inclusive('\n') {
+        // Indented four spaces it would become a code block once separated
+        let indent = line.len() - line.trim_start().len();
+        if indent < 4 && shortcodes::parse(line).is_some() {
+            lines.push(start..start + line.len());
+        }
+        start += line.len();
+    }
+
+    lines
+}
+
+fn push_html<'a>(events: impl IntoIterator<Item = Event<'a>>) -> String {
+    let mut output = String::new();
+    pulldown_cmark::html::push_html(&mut output, events.into_iter());
+
+    output
+}
+
+/// Math is opt in, with it enabled a pair of dollar signs in an existing post
+/// would start rendering as math
+fn options(math: bool) -> Options {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_GFM;
+
+    if math {
+        options | Options::ENABLE_MATH
+    } else {
+        options
+    }
+}
+
+struct Heading {
+    level: HeadingLevel,
+    id: String,
+    text: String,
+}
+
+/// Assigns a unique slug id to every heading and appends a self-link anchor
+fn anchor_headings<'a>(
+    events: impl Iterator<Item = Spanned<'a>>,
+) -> (Vec<Spanned<'a>>, Vec<Heading>) {
+    let mut output = Vec::new();
+    let mut headings = Vec::new();
+    let mut slugs = HashMap::new();
+    let mut current: Option<(usize, HeadingLevel, String)> = None;
+
+    for (event, range) in events {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                current = Some((output.len(), level, String::new()));
+                output.push((event, range));
+            }
+            Event::Text(ref text) | Event::Code(ref text) if current.is_some() => {
+                if let Some((_, _, heading_text)) = current.as_mut() {
+                    heading_text.push_str(text);
+                }
+                output.push((event, range));
+            }
+            Event::End(TagEnd::Heading(_)) if current.is_some() => {
+                let (start, level, text) = current.take().unwrap();
+                let id = format!("{}{}", ID_PREFIX, unique_slug(&mut slugs, &text));
+
+                if let (
+                    Event::Start(Tag::Heading {
+                        id: ref mut heading_id,
+                        ..
+                    }),
+                    _,
+                ) = output[start]
+                {
+                    *heading_id = Some(CowStr::from(id.clone()));
+                }
+
+                let mut anchor = String::from(r##"<a class="heading-anchor" href="#"##);
+                let _ = escape_href(&mut anchor, &id);
+                anchor.push_str(r#"" aria-hidden="true">#</a>"#);
+
+                output.push((Event::InlineHtml(anchor.into()), range.start..range.start));
+                output.push((event, range));
+
+                headings.push(Heading { level, id, text });
+            }
+            _ => output.push((event, range)),
+        }
+    }
+
+    (output, headings)
+}
+
+/// Moves footnote labels under their own id prefix, they are written out
+/// as the ids and links of the footnotes
+fn prefix_footnotes<'a>(
+    events: impl Iterator<Item = Spanned<'a>>,
+) -> impl Iterator<Item = Spanned<'a>> {
+    let prefix = |label: CowStr| CowStr::from(format!("{}{}", FOOTNOTE_ID_PREFIX, label));
+
+    events.map(move |(event, range)| match event {
+        Event::FootnoteReference(label) => (Event::FootnoteReference(prefix(label)), range),
+        Event::Start(Tag::FootnoteDefinition(label)) => {
+            (Event::Start(Tag::FootnoteDefinition(prefix(label))), range)
+        }
+        event => (event, range),
+    })
+}
+
+fn unique_slug(slugs: &mut HashMap<String, usize>, text: &str) -> String {
+    let slug = slugify(text);
+    let count = slugs.entry(slug.clone()).or_insert(0);
+    *count += 1;
+
+    if *count == 1 {
+        slug
+    } else {
+        format!("{}-{}", slug, *count - 1)
+    }
+}
+
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    let mut dash = false;
+
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() {
+            if dash && !slug.is_empty() {
+                slug.push('-');
+            }
+            dash = false;
+            slug.extend(c.to_lowercase());
+        } else if c.is_whitespace() || c == '-' || c == '_' {
+            dash = true;
+        }
+    }
+
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+
+    slug
+}
+
+/// Replaces `[[name ...]]` paragraphs and legacy `<youtube:ID>` tags with
+/// their rendered shortcodes, a `[[toc]]` paragraph becomes a nested list of
+/// links to the document's headings
+fn expand_shortcodes<'a>(
+    content: &str,
+    events: &[Spanned<'a>],
+    headings: &[Heading],
+    errors: ShortcodeErrors,
+) -> Vec<Event<'a>> {
+    let mut output = Vec::with_capacity(events.len());
+    let mut idx = 0;
+
+    while idx < events.len() {
+        let (event, range) = &events[idx];
+        match event {
+            Event::Start(Tag::Paragraph) => {
+                let source = content[range.clone()].trim();
+                if let Some(tag) = shortcodes::parse(source) {
+                    let end = paragraph_end(events, idx);
+                    let (html, next) = match tag {
+                        Ok(shortcodes::Tag::Open(name, _)) if name == "toc" => {
+                            let toc = if headings.is_empty() {
+                                Ok(String::new())
+                            } else {
+                                Ok(render_toc(headings))
+                            };
+                            (toc, end + 1)
+                        }
+                        Ok(shortcodes::Tag::Open(name, args)) => match shortcodes::lookup(&name) {
+                            Some(def) if def.paired => {
+                                match find_close(content, events, end + 1, &name) {
+                                    Some((close, close_end)) => {
+                                        let body = expand_shortcodes(
+                                            content,
+                                            &events[end + 1..close],
+                                            headings,
+                                            errors,
+                                        );
+                                        let body = push_html(body);
+                                        (def.render(&args, Some(&body)), close_end + 1)
+                                    }
+                                    None => (Err(ShortcodeError::Unclosed(name)), end + 1),
+                                }
+                            }
+                            Some(def) => (def.render(&args, None), end + 1),
+                            None => (Err(ShortcodeError::Unknown(name)), end + 1),
+                        },
+                        Ok(shortcodes::Tag::Close(name)) => {
+                            (Err(ShortcodeError::UnexpectedClose(name)), end + 1)
+                        }
+                        Err(e) => (Err(e), end + 1),
+                    };
+
+                    output.push(Event::Html(shortcode_html(source, html, errors).into()));
+                    idx = next;
+                    continue;
+                }
+            }
+            Event::InlineMath(tex) => {
+                output.push(Event::InlineHtml(math(tex, DisplayStyle::Inline).into()));
+                idx += 1;
+                continue;
+            }
+            Event::DisplayMath(tex) => {
+                output.push(Event::InlineHtml(math(tex, DisplayStyle::Block).into()));
+                idx += 1;
+                continue;
+            }
+            Event::Html(html) if html.contains(LEGACY_YOUTUBE) => {
+                output.push(Event::Html(legacy_youtube(html, errors).into()));
+                idx += 1;
+                continue;
+            }
+            Event::InlineHtml(html) if html.contains(LEGACY_YOUTUBE) => {
+                output.push(Event::InlineHtml(legacy_youtube(html, errors).into()));
+                idx += 1;
+                continue;
+            }
+            Event::Start(Tag::Link {
+                link_type: LinkType::Autolink,
+                dest_url,
+                ..
+            }) if dest_url.starts_with("youtube:") => {
+                let source = content[range.clone()].trim();
+                let id = dest_url.trim_start_matches("youtube:");
+                output.push(Event::InlineHtml(youtube(source, id, errors).into()));
+
+                while idx < events.len() && !matches!(events[idx].0, Event::End(TagEnd::Link)) {
+                    idx += 1;
+                }
+                idx += 1;
+                continue;
+            }
+            _ => (),
+        }
+
+        output.push(event.clone());
+        idx += 1;
+    }
+
+    output
+}
+
+fn paragraph_end(events: &[Spanned], start: usize) -> usize {
+    events[start..]
+        .iter()
+        .position(|(e, _)| matches!(e, Event::End(TagEnd::Paragraph)))
+        .map(|offset| start + offset)
+        .unwrap_or(events.len() - 1)
+}
+
+/// Finds the `[[/name]]` paragraph closing a paired shortcode, returning the
+/// indexes of its opening and closing events. The close must be a sibling of
+/// the opening paragraph, the search stops at the end of their container.
+fn find_close(
+    content: &str,
+    events: &[Spanned],
+    start: usize,
+    name: &str,
+) -> Option<(usize, usize)> {
+    let mut nested = 0;
+    let mut depth = 0;
+
+    for (idx, (event, range)) in events.iter().enumerate().skip(start) {
+        match event {
+            Event::Start(Tag::Paragraph) if depth == 0 => {
+                match shortcodes::parse(&content[range.clone()]) {
+                    Some(Ok(shortcodes::Tag::Open(open, _))) if open == name => nested += 1,
+                    Some(Ok(shortcodes::Tag::Close(close))) if close == name => {
+                        if nested == 0 {
+                            return Some((idx, paragraph_end(events, idx)));
+                        }
+                        nested -= 1;
+                    }
+                    _ => (),
+                }
+                depth += 1;
+            }
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => return None,
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Converts TeX to MathML, falling back to the escaped source on failure.
+/// latex2mathml copies characters from the TeX into its output verbatim, so
+/// the output is parsed again and only MathML is kept.
+fn math(tex: &str, display: DisplayStyle) -> String {
+    match latex2mathml::latex_to_mathml(tex, display) {
+        Ok(mathml) => mathml_sanitizer().clean(&mathml).to_string(),
+        Err(e) => {
+            tracing::warn!("unable to render math {:?}: {}", tex, e);
+            let mut output = String::from(r#"<code class="math-error">"#);
+            let _ = escape_html(&mut output, tex);
+            output.push_str("</code>");
+            output
+        }
+    }
+}
+
+fn shortcode_html(
+    source: &str,
+    html: Result<String, ShortcodeError>,
+    errors: ShortcodeErrors,
+) -> String {
+    html.unwrap_or_else(|e| {
+        tracing::warn!("malformed shortcode {}: {}", source, e);
+        match errors {
+            ShortcodeErrors::Shown => shortcodes::render_error(source, &e),
+            ShortcodeErrors::Hidden => String::new(),
+        }
+    })
+}
+
+fn youtube(source: &str, id: &str, errors: ShortcodeErrors) -> String {
+    let args = Args::with_positional(vec![id.to_string()]);
+    let html = shortcodes::lookup("youtube")
+        .ok_or_else(|| ShortcodeError::Unknown("youtube".into()))
+        .and_then(|def| def.render(&args, None));
+
+    shortcode_html(source, html, errors)
+}
+
+/// Expands `<youtube:ID>` tags embedded in raw html
+fn legacy_youtube(html: &str, errors: ShortcodeErrors) -> String {
+    let mut output = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find(LEGACY_YOUTUBE) {
+        output.push_str(&rest[..start]);
+
+        let tag = &rest[start..];
+        match tag.find('>') {
+            Some(end) => {
+                let id = &tag[LEGACY_YOUTUBE.len()..end];
+                output.push_str(&youtube(&tag[..=end], id, errors));
+                rest = &tag[end + 1..];
+            }
+            None => {
+                output.push_str(tag);
+                rest = "";
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+fn render_toc(headings: &[Heading]) -> String {
+    let base = headings.iter().map(|h| h.level as usize).min().unwrap_or(1);
+    let mut toc = String::from(r#"<nav class="toc">"#);
+    let mut depth = 0;
+
+    for heading in headings {
+        let level = heading.level as usize - base + 1;
+        if level > depth {
+            for _ in depth..level {
+                toc.push_str("<ul><li>");
+            }
+        } else {
+            toc.push_str("</li>");
+            for _ in level..depth {
+                toc.push_str("</ul></li>");
+            }
+            toc.push_str("<li>");
+        }
+        depth = level;
+
+        toc.push_str(r##"<a href="#"##);
+        let _ = escape_href(&mut toc, &heading.id);
+        toc.push_str(r#"">"#);
+        let _ = escape_html(&mut toc, &heading.text);
+        toc.push_str("</a>");
+    }
+
+    toc.push_str("</li>");
+    for _ in 1..depth {
+        toc.push_str("</ul></li>");
+    }
+    toc.push_str("</ul></nav>");
+
+    toc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restricted(content: &str) -> String {
+        render(
+            content,
+            options(false),
+            HtmlPolicy::Restricted,
+            ShortcodeErrors::Shown,
+        )
+    }
+
+    fn trusted(content: &str) -> String {
+        render(
+            content,
+            options(false),
+            HtmlPolicy::Trusted,
+            ShortcodeErrors::Shown,
+        )
+    }
+
+    #[test]
+    fn renders_paired_callouts() {
+        let html = trusted("[[callout warning \"Careful\"]]\n**body**\n[[/callout]]\n\nafter");
+        assert!(
+            html.contains(r#"<aside class="callout callout-warning">"#),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(r#"<p class="callout-title">Careful</p>"#),
+            "{}",
+            html
+        );
+        assert!(html.contains("<strong>body</strong>"), "{}", html);
+        assert!(
+            html.find("</aside>") < html.find("<p>after</p>"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn renders_nested_callouts() {
+        let html = trusted(
+            "[[callout]]\nouter\n\n[[callout tip]]\ninner\n[[/callout]]\n\nrest\n[[/callout]]",
+        );
+        let outer = html.find("callout-note").unwrap();
+        let inner = html.find("callout-tip").unwrap();
+        let inner_end = html.find("</aside>").unwrap();
+        let rest = html.find("rest").unwrap();
+        assert!(
+            outer < inner && inner < inner_end && inner_end < rest,
+            "{}",
+            html
+        );
+        assert_eq!(html.matches("</aside>").count(), 2, "{}", html);
+        assert!(!html.contains("shortcode-error"), "{}", html);
+    }
+
+    #[test]
+    fn closes_shortcodes_within_their_container() {
+        let html = trusted("- item\n\n  [[callout]]\n\n  body\n\n  [[/callout]]\n\n- next");
+        let item = html.find("<li>").unwrap();
+        let aside = html.find("<aside").unwrap();
+        let item_end = html.find("</li>").unwrap();
+        assert!(item < aside && aside < item_end, "{}", html);
+        assert!(!html.contains("shortcode-error"), "{}", html);
+
+        let html = trusted("[[callout]]\n\n- item\n\n  [[/callout]]");
+        assert!(html.contains("missing closing [[/callout]]"), "{}", html);
+        assert!(
+            html.contains("[[/callout]] without a matching opening shortcode"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn isolates_shortcode_lines() {
+        let content = "before\n[[toc]]\nafter";
+        assert_eq!(
+            isolate_shortcodes(content, options(false)),
+            "before\n\n[[toc]]\n\nafter"
+        );
+
+        // Lists stay tight and indented code stays code
+        for content in [
+            "- a\n  [[youtube abcdefghijk]]\n- b",
+            "> a\n> [[toc]]",
+            "[[toc]]",
+            "text\n    [[toc]]",
+        ] {
+            assert!(
+                matches!(
+                    isolate_shortcodes(content, options(false)),
+                    Cow::Borrowed(_)
+                ),
+                "{}",
+                content
+            );
+        }
+
+        let html = trusted("- a\n  [[youtube abcdefghijk]]\n- b");
+        assert!(!html.contains("<li><p>"), "{}", html);
+    }
+
+    #[test]
+    fn finds_closing_shortcodes() {
+        let find = |content: &str| {
+            let events: Vec<_> = Parser::new_ext(content, options(false))
+                .into_offset_iter()
+                .collect();
+            let start = paragraph_end(&events, 0) + 1;
+            find_close(content, &events, start, "callout").map(|(close, end)| {
+                assert!(matches!(events[end].0, Event::End(TagEnd::Paragraph)));
+                content[events[close].1.clone()].trim().to_string()
+            })
+        };
+
+        assert_eq!(
+            find("[[callout]]\n\nbody\n\n[[/callout]]").as_deref(),
+            Some("[[/callout]]")
+        );
+        assert_eq!(
+            find("[[callout]]\n\n[[callout]]\n\n[[/callout]]\n\n[[/callout]]x").as_deref(),
+            None
+        );
+        assert_eq!(find("[[callout]]\n\n> [[/callout]]"), None);
+        assert_eq!(find("[[callout]]\n\nbody"), None);
+    }
+
+    #[test]
+    fn expands_legacy_youtube() {
+        let html = trusted("<youtube:abcdefghijk>");
+        assert!(html.contains(r#"data-video-id="abcdefghijk""#), "{}", html);
+
+        let html = trusted("watch <youtube:abcdefghijk> now");
+        assert!(html.contains(r#"data-video-id="abcdefghijk""#), "{}", html);
+        assert!(html.contains("now"), "{}", html);
+
+        let html = trusted("<div>\n<youtube:abcdefghijk>\n</div>");
+        assert!(html.contains(r#"data-video-id="abcdefghijk""#), "{}", html);
+
+        let html = trusted("<youtube:bad.id>");
+        assert!(html.contains("shortcode-error"), "{}", html);
+
+        let html = render(
+            "<youtube:bad.id>",
+            options(false),
+            HtmlPolicy::Trusted,
+            ShortcodeErrors::Hidden,
+        );
+        assert!(!html.contains("shortcode-error"), "{}", html);
+    }
+
+    fn with_math(content: &str) -> String {
+        render(
+            content,
+            options(true),
+            HtmlPolicy::Trusted,
+            ShortcodeErrors::Shown,
+        )
+    }
+
+    #[test]
+    fn renders_math() {
+        let html = with_math("$x^2$");
+        assert!(html.contains("<math"), "{}", html);
+        assert!(html.contains("<msup>"), "{}", html);
+
+        let html = with_math("$$\\frac{1}{2}$$");
+        assert!(html.contains(r#"display="block""#), "{}", html);
+        assert!(html.contains("<mfrac>"), "{}", html);
+    }
+
+    #[test]
+    fn sanitizes_math() {
+        for tex in [
+            r"$\text{<img src=x onerror=alert(1)>}$",
+            r#"$\text{"><img src=x onerror=alert(1)>}$"#,
+            r"$\mathrm{</math><script>alert(1)</script>}$",
+        ] {
+            let html = with_math(tex);
+            assert!(html.contains("<math"), "{}", html);
+            assert!(!html.contains("<img"), "{}", html);
+            assert!(!html.contains("<script"), "{}", html);
+            assert!(html.ends_with("</math></p>\n"), "{}", html);
+        }
+
+        // latex2mathml copies the operator verbatim
+        let html = with_math("$a < b$");
+        assert!(html.contains("<mo>&lt;</mo>"), "{}", html);
+
+        // An html element breaks out of the math element as it would in a page
+        let mathml = mathml_sanitizer().clean(
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML"><mi onclick="alert(1)" mathvariant="bold">a</mi><img src=x onerror=alert(1)><a href="javascript:alert(1)">b</a></math>"#,
+        );
+        assert_eq!(
+            mathml.to_string(),
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML"><mi mathvariant="bold">a</mi></math>b"#
+        );
+    }
+
+    #[test]
+    fn leaves_dollar_amounts_as_text() {
+        let html = with_math("It costs $5 and $10 to enter");
+        assert_eq!(html, "<p>It costs $5 and $10 to enter</p>\n");
+
+        let html = trusted("$x^2$");
+        assert_eq!(html, "<p>$x^2$</p>\n");
+    }
+
+    #[test]
+    fn sanitizes_ids() {
+        let html = restricted(r#"<div id="user-bar">x</div> <h2 id="header">y</h2>"#);
+        assert!(!html.contains("user-bar"), "{}", html);
+        assert!(!html.contains(r#"id="header""#), "{}", html);
+
+        let html = restricted("## User bar\n\nnote[^user-bar]\n\n[^user-bar]: footnote");
+        assert!(html.contains(r#"<h2 id="content-user-bar">"#), "{}", html);
+        assert!(
+            html.contains(r#"<div class="footnote-definition" id="content-fn-user-bar">"#),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains(r##"href="#content-fn-user-bar""##),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn sanitizes_classes() {
+        let html = restricted(r#"<div class="header youtube-container">x</div>"#);
+        assert!(
+            html.contains(r#"<div class="youtube-container">"#),
+            "{}",
+            html
+        );
+
+        let html = restricted(r#"<span class="header">x</span>"#);
+        assert!(!html.contains("header"), "{}", html);
+
+        let html = restricted("```rust\nfn main() {}\n```");
+        assert!(html.contains(r#"<code class="language-rust">"#), "{}", html);
+
+        let html = restricted(r#"<code class="header">x</code>"#);
+        assert!(!html.contains("header"), "{}", html);
+    }
+}
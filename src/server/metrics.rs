@@ -0,0 +1,144 @@
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    pub redis_pool_size: IntGauge,
+    pub redis_pool_available: IntGauge,
+    pub redis_pool_max_size: IntGauge,
+    pub redis_pool_wait: Histogram,
+    pub sessions_created: IntCounter,
+    pub logins: IntCounterVec,
+    pub markdown_render_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("nickmass".into()), None)?;
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests served by route and status"),
+            &["method", "route", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time to produce a response by route",
+            ),
+            &["method", "route"],
+        )?;
+        let redis_pool_size = IntGauge::new(
+            "redis_pool_connections",
+            "Connections currently open in the redis pool",
+        )?;
+        let redis_pool_available = IntGauge::new(
+            "redis_pool_available",
+            "Idle redis connections, negative when requests are waiting",
+        )?;
+        let redis_pool_max_size = IntGauge::new(
+            "redis_pool_max_connections",
+            "The most connections the redis pool will open",
+        )?;
+        let redis_pool_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "redis_pool_wait_seconds",
+                "Time spent waiting for a redis connection",
+            )
+            .buckets(vec![
+                0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+            ]),
+        )?;
+        let sessions_created = IntCounter::new("sessions_created_total", "New sessions stored")?;
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Oauth login attempts by outcome"),
+            &["result"],
+        )?;
+        let markdown_render_duration = Histogram::with_opts(HistogramOpts::new(
+            "markdown_render_duration_seconds",
+            "Time to render a post from markdown, excluding cache hits",
+        ))?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(redis_pool_size.clone()))?;
+        registry.register(Box::new(redis_pool_available.clone()))?;
+        registry.register(Box::new(redis_pool_max_size.clone()))?;
+        registry.register(Box::new(redis_pool_wait.clone()))?;
+        registry.register(Box::new(sessions_created.clone()))?;
+        registry.register(Box::new(logins.clone()))?;
+        registry.register(Box::new(markdown_render_duration.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            redis_pool_size,
+            redis_pool_available,
+            redis_pool_max_size,
+            redis_pool_wait,
+            sessions_created,
+            logins,
+            markdown_render_duration,
+        })
+    }
+
+    pub fn login_succeeded(&self) {
+        self.logins.with_label_values(&["success"]).inc();
+    }
+
+    pub fn login_failed(&self) {
+        self.logins.with_label_values(&["failure"]).inc();
+    }
+
+    /// Renders every metric in the prometheus text exposition format
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::error!("unable to encode metrics: {}", err);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Valid metric definitions"))
+}
+
+/// Records request counts and latency labelled by the matched route template
+/// rather than the raw path so ids don't explode the label cardinality
+pub async fn track_requests(req: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.run(req).await;
+
+    let metrics = metrics();
+    metrics
+        .http_requests
+        .with_label_values(&[method.as_str(), &route, res.status().as_str()])
+        .inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&[method.as_str(), &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    res
+}
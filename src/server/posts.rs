@@ -22,6 +22,8 @@ pub struct Post {
     #[serde(skip_deserializing)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub author: Option<String>,
+    #[serde(skip)]
+    pub author_role: Option<String>,
 }
 
 struct MaybePost(Option<Post>);
@@ -30,7 +32,7 @@ impl redis::FromRedisValue for MaybePost {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<MaybePost> {
         match HashMap::<String, String>::from_redis_value(v) {
             Ok(mut h) => {
-                if h.len() == 0 {
+                if h.is_empty() {
                     return Ok(MaybePost(None));
                 }
                 let if_error = |s| (redis::ErrorKind::ResponseError, s);
@@ -64,6 +66,7 @@ impl redis::FromRedisValue for MaybePost {
                     title,
                     url_fragment,
                     author: None,
+                    author_role: None,
                 })))
             }
             Err(e) => Err(e),
@@ -84,6 +87,14 @@ pub struct PostPage {
     pub total: i64,
 }
 
+/// Bumped on every post write so cached pages can be revalidated without
+/// reading every post
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Revision {
+    pub counter: u64,
+    pub modified: u64,
+}
+
 pub struct PostClient {
     db: Connection,
 }
@@ -94,7 +105,7 @@ impl PostClient {
     }
 
     #[tracing::instrument(name = "post::get_all", skip_all, err)]
-    pub async fn get_all(mut self, limit: i64, skip: i64) -> Result<PostPage, Error> {
+    pub async fn get_all(&mut self, limit: i64, skip: i64) -> Result<PostPage, Error> {
         let post_ids: Vec<i64> = redis::cmd("lrange")
             .arg("posts")
             .arg(skip)
@@ -126,7 +137,10 @@ impl PostClient {
         let author_map: HashMap<_, _> = authors.into_iter().map(|u| (u.id, u)).collect();
 
         posts.iter_mut().for_each(|p| {
-            p.author = author_map.get(&p.author_id).map(|u| u.name.clone());
+            if let Some(author) = author_map.get(&p.author_id) {
+                p.author = Some(author.name.clone());
+                p.author_role = Some(author.role.clone());
+            }
         });
 
         let total: i64 = redis::cmd("llen")
@@ -140,13 +154,27 @@ impl PostClient {
         })
     }
 
+    #[tracing::instrument(name = "post::revision", skip_all, err)]
+    pub async fn revision(&mut self) -> Result<Revision, Error> {
+        let (counter, modified): (Option<u64>, Option<u64>) = redis::cmd("mget")
+            .arg("postsRevision")
+            .arg("postsModified")
+            .query_async(&mut self.db)
+            .await?;
+
+        Ok(Revision {
+            counter: counter.unwrap_or(0),
+            modified: modified.unwrap_or(0),
+        })
+    }
+
     #[tracing::instrument(name = "post::get", skip_all, err)]
-    pub async fn get(mut self, id: u64) -> Result<Post, Error> {
+    pub async fn get(&mut self, id: u64) -> Result<Post, Error> {
         Self::get_by_id(&mut self.db, id).await
     }
 
     #[tracing::instrument(name = "post::get_by_fragment", skip_all, err)]
-    pub async fn get_by_fragment(mut self, fragment: impl AsRef<str>) -> Result<Post, Error> {
+    pub async fn get_by_fragment(&mut self, fragment: impl AsRef<str>) -> Result<Post, Error> {
         let fragment_key: String = format!("postFragment:{}", fragment.as_ref());
         let id = redis::cmd("get")
             .arg(fragment_key)
@@ -166,12 +194,11 @@ impl PostClient {
         let post: MaybePost = redis::cmd("hgetall").arg(post_key).query_async(db).await?;
         if let Some(mut post) = Option::<Post>::from(post) {
             let author: String = format!("user:{}", post.author_id);
-            let author = redis::cmd("hget")
-                .arg(author)
-                .arg("name")
-                .query_async(db)
-                .await?;
-            post.author = author;
+            let author: MaybeUser = redis::cmd("hgetall").arg(author).query_async(db).await?;
+            if let Some(author) = Option::<User>::from(author) {
+                post.author = Some(author.name);
+                post.author_role = Some(author.role);
+            }
             Ok(post)
         } else {
             Err(Error::ResourceNotFound(Resource::Post(id)))
@@ -211,6 +238,8 @@ impl Authenticated<PostClient> {
             ],
         )
         .ignore();
+        pipe.incr("postsRevision", 1).ignore();
+        pipe.set("postsModified", post.date).ignore();
 
         let _: () = pipe.query_async(&mut self.db).await?;
         let _: () = redis::cmd("bgsave").query_async(&mut self.db).await?;
@@ -240,6 +269,12 @@ impl Authenticated<PostClient> {
                 ],
             )
             .ignore();
+            pipe.incr("postsRevision", 1).ignore();
+            pipe.set(
+                "postsModified",
+                chrono::Utc::now().timestamp_millis() as u64,
+            )
+            .ignore();
 
             let _: () = pipe.query_async(&mut self.db).await?;
             let _: () = redis::cmd("bgsave").query_async(&mut self.db).await?;
@@ -0,0 +1,185 @@
+use ipnet::Ipv6Net;
+use redis::Script;
+
+use super::config::RateBudget;
+use super::db::Db;
+use super::{Config, Error};
+
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Refills the bucket for the time elapsed since it was last touched, then
+/// takes a token if one is available. Returns 0 when the request may
+/// proceed, otherwise the milliseconds until a token will be available.
+/// Uses the redis clock so every replica agrees on elapsed time.
+const TOKEN_BUCKET: &str = r#"
+redis.replicate_commands()
+
+local capacity = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or capacity
+local updated = tonumber(state[2]) or now
+
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * capacity / period)
+
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * period / capacity)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('PEXPIRE', KEYS[1], period)
+
+return wait
+"#;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Group {
+    Auth,
+    Session,
+    ApiWrite,
+    CspReport,
+}
+
+impl Group {
+    fn name(&self) -> &'static str {
+        match self {
+            Group::Auth => "auth",
+            Group::Session => "session",
+            Group::ApiWrite => "apiWrite",
+            Group::CspReport => "cspReport",
+        }
+    }
+}
+
+/// Ipv6 clients are limited by network rather than by address
+const IPV6_PREFIX: u8 = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Key {
+    Ip(IpAddr),
+    User(u64),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Ip(ip) => match ip.to_canonical() {
+                IpAddr::V4(ip) => write!(f, "ip:{}", ip),
+                // A client is usually handed a whole /64 to pick addresses from
+                IpAddr::V6(ip) => {
+                    let net = Ipv6Net::new(ip, IPV6_PREFIX).expect("Valid ipv6 prefix length");
+                    write!(f, "ip:{}", net.trunc())
+                }
+            },
+            Key::User(id) => write!(f, "user:{}", id),
+        }
+    }
+}
+
+/// Token buckets shared between replicas through redis, each group of
+/// routes has its own budget
+pub struct RateLimiter {
+    db: Db,
+    script: Script,
+    limits: RwLock<Limits>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    enabled: bool,
+    auth: RateBudget,
+    session: RateBudget,
+    api_write: RateBudget,
+    csp_report: RateBudget,
+}
+
+impl Limits {
+    fn new(config: &Config) -> Limits {
+        Limits {
+            enabled: config.rate_limit,
+            auth: config.rate_limit_auth,
+            session: config.rate_limit_session,
+            api_write: config.rate_limit_api_write,
+            csp_report: config.rate_limit_csp_report,
+        }
+    }
+
+    fn budget(&self, group: Group) -> RateBudget {
+        match group {
+            Group::Auth => self.auth,
+            Group::Session => self.session,
+            Group::ApiWrite => self.api_write,
+            Group::CspReport => self.csp_report,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config, db: Db) -> RateLimiter {
+        RateLimiter {
+            db,
+            script: Script::new(TOKEN_BUCKET),
+            limits: RwLock::new(Limits::new(config)),
+        }
+    }
+
+    /// Switches to the budgets of a reloaded config, buckets already in redis
+    /// take on the new capacity the next time they are used
+    pub fn update(&self, config: &Config) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = Limits::new(config);
+    }
+
+    /// Takes a token from the bucket of every key, failing with the longest
+    /// wait when any of them is empty. Requests are allowed through if
+    /// redis is unavailable rather than taking the site down with it.
+    #[tracing::instrument(name = "rate_limit::check", skip_all)]
+    pub async fn check(&self, group: Group, keys: &[Key]) -> Result<(), Error> {
+        let limits = *self.limits.read().unwrap_or_else(|e| e.into_inner());
+        if !limits.enabled {
+            return Ok(());
+        }
+
+        let budget = limits.budget(group);
+        let period_ms = budget.seconds as u64 * 1000;
+
+        let mut connection = match self.db.get().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                tracing::warn!("skipping rate limit, unable to reach redis: {}", err);
+                return Ok(());
+            }
+        };
+
+        let mut wait_ms = 0;
+        for key in keys {
+            let wait: Result<u64, _> = self
+                .script
+                .key(format!("rateLimit:{}:{}", group.name(), key))
+                .arg(budget.requests)
+                .arg(period_ms)
+                .invoke_async(&mut connection)
+                .await;
+
+            match wait {
+                Ok(wait) => wait_ms = wait_ms.max(wait),
+                Err(err) => tracing::warn!("skipping rate limit for {}: {}", key, err),
+            }
+        }
+
+        if wait_ms > 0 {
+            tracing::info!(group = group.name(), "rate limit exceeded");
+            Err(Error::RateLimited(Duration::from_millis(wait_ms)))
+        } else {
+            Ok(())
+        }
+    }
+}
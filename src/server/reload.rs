@@ -0,0 +1,114 @@
+use tower_http::services::ServeDir;
+
+use super::csp::Policy;
+use super::rate_limit::RateLimiter;
+use super::security::SecurityHeaders;
+use super::{assets, Config};
+
+use std::sync::{Arc, RwLock};
+
+/// A value that can be replaced while the server runs, readers take a
+/// snapshot and always see either the old or the new value in full
+pub struct Reloadable<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Reloadable<T> {
+        Reloadable {
+            current: Arc::new(RwLock::new(Arc::new(value))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<T> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn replace(&self, value: T) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(value);
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Reloadable {
+            current: self.current.clone(),
+        }
+    }
+}
+
+/// Everything built from settings that may change without a restart
+pub struct Reloader {
+    pub config: Reloadable<Config>,
+    pub csp: Reloadable<Policy>,
+    pub security_headers: Reloadable<SecurityHeaders>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub static_files: Reloadable<ServeDir>,
+    pub logging: Box<dyn Fn(&Config) + Send + Sync>,
+}
+
+impl Reloader {
+    /// Reloads the config on every SIGHUP. A config with problems is
+    /// rejected as a whole and the server carries on with the old one.
+    pub async fn watch(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("unable to listen to sighup");
+
+        while hangup.recv().await.is_some() {
+            tracing::info!("reloading config");
+            match Config::reload() {
+                Ok(config) => self.apply(config),
+                Err(errors) => {
+                    for error in errors {
+                        tracing::error!("invalid config: {}", error);
+                    }
+                    tracing::error!("config reload failed, keeping the current config");
+                }
+            }
+        }
+    }
+
+    fn apply(&self, new: Config) {
+        let reload = self.config.current().reload_from(new);
+        let config = reload.config;
+
+        self.csp.replace(Policy::new(&config));
+        self.security_headers.replace(SecurityHeaders::new(&config));
+        self.rate_limiter.update(&config);
+        (self.logging)(&config);
+
+        // Rebuilt even when asset_dir is unchanged, new assets may have been
+        // deployed into it. Pages cached with the old urls are revalidated
+        // and the old fingerprints are still answered with the current files.
+        match assets::AssetManifest::build(config.asset_dir.as_str()) {
+            Ok(manifest) => {
+                let count = manifest.len();
+                if assets::install(manifest) {
+                    tracing::info!("fingerprinted {} assets, page validators updated", count);
+                } else {
+                    tracing::info!("assets unchanged");
+                }
+            }
+            Err(err) => tracing::warn!("unable to fingerprint assets: {}", err),
+        }
+        self.static_files.replace(assets::serve_dir(&config));
+
+        self.config.replace(config);
+
+        if reload.changed.is_empty() {
+            tracing::info!("config reloaded, no settings changed");
+        } else {
+            tracing::info!("config reloaded, changed: {}", reload.changed.join(", "));
+        }
+        if !reload.restart_required.is_empty() {
+            tracing::warn!(
+                "restart required to apply: {}",
+                reload.restart_required.join(", ")
+            );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use axum::body::Body;
+use axum::http::{HeaderName, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use std::fmt;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifies a request across our logs, the response headers and any error
+/// shown to the user
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> RequestId {
+        RequestId(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Accepts ids from upstream proxies only when they are short and made
+    /// of characters that are safe to echo into headers, logs and html
+    fn parse(value: &HeaderValue) -> Option<RequestId> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+
+        valid.then(|| RequestId(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The id of the request being handled by the current task
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Honours a valid incoming X-Request-Id or assigns a new one, making it
+/// available to the rest of the request and echoing it on the response
+pub async fn propagate(mut req: Request<Body>, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+
+    req.extensions_mut().insert(id.clone());
+    let mut res = CURRENT.scope(id.clone(), next.run(req)).await;
+
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        res.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+
+    res
+}
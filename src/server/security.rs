@@ -0,0 +1,127 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderName, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use super::forwarded::{ForwardedProto, Proto};
+use super::reload::Reloadable;
+use super::Config;
+
+/// The kinds of routes, each sends only the headers that mean something
+/// for its responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Html,
+    Api,
+    Static,
+}
+
+/// Hardening headers sent with every response, values come from config and
+/// an empty value leaves that header out
+pub struct SecurityHeaders {
+    enabled: bool,
+    tls: bool,
+    hsts: Option<HeaderValue>,
+    referrer_policy: Option<HeaderValue>,
+    permissions_policy: Option<HeaderValue>,
+    cross_origin_opener_policy: Option<HeaderValue>,
+    cross_origin_resource_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> SecurityHeaders {
+        let value = |name: &str, value: &str| {
+            if value.is_empty() {
+                return None;
+            }
+            HeaderValue::from_str(value)
+                .map_err(|_| tracing::warn!("ignoring invalid {} value: {}", name, value))
+                .ok()
+        };
+
+        let hsts = (config.hsts_max_age > 0).then(|| {
+            let mut directive = format!("max-age={}", config.hsts_max_age);
+            if config.hsts_include_subdomains {
+                directive.push_str("; includeSubDomains");
+            }
+            HeaderValue::from_str(&directive).expect("Valid hsts directive")
+        });
+
+        SecurityHeaders {
+            enabled: config.security_headers,
+            tls: config.tls_enabled(),
+            hsts,
+            referrer_policy: value("referrer_policy", &config.referrer_policy),
+            permissions_policy: value("permissions_policy", &config.permissions_policy),
+            cross_origin_opener_policy: value(
+                "cross_origin_opener_policy",
+                &config.cross_origin_opener_policy,
+            ),
+            cross_origin_resource_policy: value(
+                "cross_origin_resource_policy",
+                &config.cross_origin_resource_policy,
+            ),
+        }
+    }
+
+    fn headers(&self, group: Group, https: bool) -> Vec<(HeaderName, HeaderValue)> {
+        let nosniff = Some(HeaderValue::from_static("nosniff"));
+        let html = group == Group::Html;
+        let not_static = group != Group::Static;
+
+        // Browsers ignore HSTS sent over plain http, only claim it when the
+        // visitor actually connected securely
+        let headers = [
+            (
+                header::STRICT_TRANSPORT_SECURITY,
+                self.hsts.clone().filter(|_| https),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, nosniff),
+            (
+                header::REFERRER_POLICY,
+                self.referrer_policy.clone().filter(|_| not_static),
+            ),
+            (
+                HeaderName::from_static("permissions-policy"),
+                self.permissions_policy.clone().filter(|_| html),
+            ),
+            (
+                HeaderName::from_static("cross-origin-opener-policy"),
+                self.cross_origin_opener_policy.clone().filter(|_| html),
+            ),
+            (
+                HeaderName::from_static("cross-origin-resource-policy"),
+                self.cross_origin_resource_policy.clone(),
+            ),
+        ];
+
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value)))
+            .collect()
+    }
+}
+
+pub async fn apply(
+    State((headers, group)): State<(Reloadable<SecurityHeaders>, Group)>,
+    proto: Option<ForwardedProto>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+    let headers = headers.current();
+    if !headers.enabled {
+        return res;
+    }
+
+    let https = headers.tls || matches!(proto, Some(ForwardedProto(Some(Proto::Https))));
+    let res_headers = res.headers_mut();
+    for (name, value) in headers.headers(group, https) {
+        if !res_headers.contains_key(&name) {
+            res_headers.insert(name, value);
+        }
+    }
+
+    res
+}
@@ -1,11 +1,23 @@
 use ring::aead;
 use ring::rand::{SecureRandom, SystemRandom};
 
-use super::db::Connection;
+use super::db::{Connection, Db};
+use super::metrics::metrics;
+use super::Error;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sessions left idle this long are removed from redis
+const SESSION_TTL: usize = 60 * 60 * 24 * 90;
+
+/// How often a session in use has its expiry pushed back
+const REFRESH_INTERVAL: u64 = 60 * 60 * 24;
+
+/// The session field holding when the session was last saved or refreshed
+const REFRESHED: &str = "refreshed";
 
 pub struct Session {
     rand: SystemRandom,
@@ -21,38 +33,81 @@ impl Session {
         Session { rand, key }
     }
 
+    /// Loads the session named by a sid cookie, an invalid sid is treated as
+    /// no session at all
     #[tracing::instrument(name = "session::load", skip_all)]
     pub async fn get_store(
         &self,
-        db: &mut Connection,
+        db: &Db,
         addr: IpAddr,
-        sid: Option<impl AsRef<str>>,
-    ) -> SessionStore {
-        if let Some((sid, key)) =
-            sid.and_then(|sid| self.decode_sid(addr, &sid).map(|key| (sid, key)))
-        {
-            let session_key = format!("session:{}", key);
-            let store = redis::cmd("hgetall").arg(session_key).query_async(db).await;
-
-            let store = match store {
-                Ok(hash) => SessionStore::new(key, sid.as_ref(), hash),
-                Err(_) => SessionStore::empty(key, sid.as_ref()),
-            };
-            store
-        } else {
-            let key = self.create_key();
-            let sid = self.create_sid(&key, addr);
-            SessionStore::empty(key, sid)
+        sid: impl AsRef<str>,
+    ) -> Result<SessionStore, Error> {
+        let sid = sid.as_ref();
+        let Some(key) = self.decode_sid(addr, sid) else {
+            return Ok(SessionStore::anonymous());
+        };
+
+        let mut connection = db.get().await?;
+        let session_key = format!("session:{}", key);
+        let store = redis::cmd("hgetall")
+            .arg(session_key)
+            .query_async(&mut connection)
+            .await;
+
+        match store {
+            Ok(hash) => Ok(SessionStore::new(key, sid, hash)),
+            Err(_) => Ok(SessionStore::new(key, sid, HashMap::new())),
         }
     }
 
+    /// Writes the fields changed during this request, starting a new session
+    /// if the store did not have one. Returns the sid to hand to the client,
+    /// or nothing when there was nothing to save.
     #[tracing::instrument(name = "session::save", skip_all)]
-    pub async fn set_store(&self, db: &mut Connection, store: SessionStore) {
+    pub async fn set_store(
+        &self,
+        db: &mut Connection,
+        addr: IpAddr,
+        store: SessionStore,
+    ) -> Option<String> {
+        let mut changes = store.take_changes();
+        if changes.is_empty() {
+            return None;
+        }
+        changes.push((REFRESHED.to_string(), now().to_string()));
+
+        let id = match store.id {
+            Some(id) => id,
+            None => {
+                metrics().sessions_created.inc();
+                let key = self.create_key();
+                let sid = self.create_sid(&key, addr);
+                Arc::new(SessionId { key, sid })
+            }
+        };
+
+        let mut pipe = redis::pipe();
+        let session_key = format!("session:{}", id.key);
+        pipe.hset_multiple(session_key.as_str(), changes.as_slice());
+        pipe.expire(session_key.as_str(), SESSION_TTL);
+        let _: Result<(), _> = pipe.query_async(db).await;
+
+        Some(id.sid.clone())
+    }
+
+    /// Pushes back the expiry of a session that has not been saved for a
+    /// while, returning the sid to hand to the client again
+    #[tracing::instrument(name = "session::refresh", skip_all)]
+    pub async fn refresh(&self, db: &mut Connection, store: &SessionStore) -> Option<String> {
+        let id = store.id.as_ref()?;
+
         let mut pipe = redis::pipe();
-        let session_key = format!("session:{}", store.key);
-        pipe.hset_multiple(session_key.as_str(), store.values().as_slice());
-        pipe.expire(session_key.as_str(), 60 * 60 * 24 * 90);
+        let session_key = format!("session:{}", id.key);
+        pipe.hset(session_key.as_str(), REFRESHED, now());
+        pipe.expire(session_key.as_str(), SESSION_TTL);
         let _: Result<(), _> = pipe.query_async(db).await;
+
+        Some(id.sid.clone())
     }
 
     fn decode_sid(&self, addr: IpAddr, sid: impl AsRef<str>) -> Option<String> {
@@ -102,7 +157,7 @@ impl Session {
         self.rand
             .fill(&mut nonce_bytes)
             .expect("Crypto error, could not fill sid nonce");
-        let nonce_str = base64::encode(&nonce_bytes);
+        let nonce_str = base64::encode(nonce_bytes);
         let nonce = aead::Nonce::assume_unique_for_key(nonce_bytes);
 
         let mut sid: Vec<u8> = Vec::new();
@@ -116,48 +171,102 @@ impl Session {
     }
 }
 
+#[derive(Debug)]
+struct SessionId {
+    key: String,
+    sid: String,
+}
+
+#[derive(Debug, Default)]
+struct StoreData {
+    values: HashMap<String, String>,
+    dirty: HashSet<String>,
+}
+
+/// Session values for a single request, a store without an id has not been
+/// saved yet and only becomes a session once something is written to it
 #[derive(Debug, Clone)]
 pub struct SessionStore {
-    key: Arc<String>,
-    sid: Arc<String>,
-    inner: Arc<Mutex<HashMap<String, String>>>,
+    id: Option<Arc<SessionId>>,
+    inner: Arc<Mutex<StoreData>>,
 }
 
 impl SessionStore {
     fn new(
         key: impl Into<String>,
         sid: impl Into<String>,
-        data: HashMap<String, String>,
+        values: HashMap<String, String>,
     ) -> SessionStore {
         SessionStore {
-            key: Arc::new(key.into()),
-            sid: Arc::new(sid.into()),
-            inner: Arc::new(Mutex::new(data)),
+            id: Some(Arc::new(SessionId {
+                key: key.into(),
+                sid: sid.into(),
+            })),
+            inner: Arc::new(Mutex::new(StoreData {
+                values,
+                dirty: HashSet::new(),
+            })),
         }
     }
 
-    fn empty(key: impl Into<String>, sid: impl Into<String>) -> SessionStore {
+    pub fn anonymous() -> SessionStore {
         SessionStore {
-            key: Arc::new(key.into()),
-            sid: Arc::new(sid.into()),
-            inner: Arc::new(Mutex::new(HashMap::new())),
+            id: None,
+            inner: Default::default(),
         }
     }
 
-    fn values(&self) -> Vec<(String, String)> {
-        let hash = self.inner.lock().unwrap();
-        hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    fn take_changes(&self) -> Vec<(String, String)> {
+        let mut data = self.inner.lock().unwrap();
+        let dirty = std::mem::take(&mut data.dirty);
+        dirty
+            .into_iter()
+            .filter_map(|k| data.values.get(&k).map(|v| (k, v.clone())))
+            .collect()
     }
 
     pub fn get(&self, key: impl AsRef<str>) -> Option<String> {
-        self.inner.lock().unwrap().get(key.as_ref()).cloned()
+        self.inner.lock().unwrap().values.get(key.as_ref()).cloned()
     }
 
     pub fn set(&self, key: impl Into<String>, value: impl Into<String>) {
-        self.inner.lock().unwrap().insert(key.into(), value.into());
+        let (key, value) = (key.into(), value.into());
+        let mut data = self.inner.lock().unwrap();
+        if data.values.get(&key) != Some(&value) {
+            data.dirty.insert(key.clone());
+            data.values.insert(key, value);
+        }
+    }
+
+    /// Whether saving this store would start a new session
+    pub fn is_created(&self) -> bool {
+        self.id.is_none()
+    }
+
+    /// Whether a loaded session was last saved or refreshed long enough ago
+    /// that its expiry should be pushed back
+    pub fn needs_refresh(&self) -> bool {
+        let data = self.inner.lock().unwrap();
+
+        // An expired session has no values left to keep
+        self.id.is_some()
+            && !data.values.is_empty()
+            && data
+                .values
+                .get(REFRESHED)
+                .and_then(|refreshed| refreshed.parse::<u64>().ok())
+                .is_none_or(|refreshed| now().saturating_sub(refreshed) >= REFRESH_INTERVAL)
     }
 
-    pub fn sid(&self) -> String {
-        self.sid.to_string()
+    /// Whether any values have changed since the store was loaded
+    pub fn is_dirty(&self) -> bool {
+        !self.inner.lock().unwrap().dirty.is_empty()
     }
 }
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
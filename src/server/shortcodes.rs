@@ -0,0 +1,545 @@
+use askama::Template;
+
+use std::collections::HashMap;
+use std::fmt;
+
+pub struct ShortcodeDef {
+    pub name: &'static str,
+    /// Paired shortcodes wrap a body of markdown terminated by `[[/name]]`
+    pub paired: bool,
+    render: fn(&Args, Option<&str>) -> Result<String, ShortcodeError>,
+}
+
+impl ShortcodeDef {
+    pub fn render(&self, args: &Args, body: Option<&str>) -> Result<String, ShortcodeError> {
+        (self.render)(args, body)
+    }
+}
+
+const SHORTCODES: &[ShortcodeDef] = &[
+    ShortcodeDef {
+        name: "youtube",
+        paired: false,
+        render: render_youtube,
+    },
+    ShortcodeDef {
+        name: "vimeo",
+        paired: false,
+        render: render_vimeo,
+    },
+    ShortcodeDef {
+        name: "gist",
+        paired: false,
+        render: render_gist,
+    },
+    ShortcodeDef {
+        name: "figure",
+        paired: false,
+        render: render_figure,
+    },
+    ShortcodeDef {
+        name: "callout",
+        paired: true,
+        render: render_callout,
+    },
+    ShortcodeDef {
+        name: "audio",
+        paired: false,
+        render: render_audio,
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static ShortcodeDef> {
+    SHORTCODES.iter().find(|s| s.name == name)
+}
+
+#[derive(Debug)]
+pub enum ShortcodeError {
+    Syntax(String),
+    Unknown(String),
+    MissingArg(&'static str),
+    InvalidArg(&'static str, String),
+    Unclosed(String),
+    UnexpectedClose(String),
+    Render(askama::Error),
+}
+
+impl fmt::Display for ShortcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShortcodeError::Syntax(msg) => write!(f, "{}", msg),
+            ShortcodeError::Unknown(name) => write!(f, "unknown shortcode '{}'", name),
+            ShortcodeError::MissingArg(arg) => write!(f, "missing required argument '{}'", arg),
+            ShortcodeError::InvalidArg(arg, value) => {
+                write!(f, "invalid value for '{}': '{}'", arg, value)
+            }
+            ShortcodeError::Unclosed(name) => write!(f, "missing closing [[/{}]]", name),
+            ShortcodeError::UnexpectedClose(name) => {
+                write!(f, "[[/{}]] without a matching opening shortcode", name)
+            }
+            ShortcodeError::Render(err) => write!(f, "failed to render: {}", err),
+        }
+    }
+}
+
+impl From<askama::Error> for ShortcodeError {
+    fn from(other: askama::Error) -> Self {
+        ShortcodeError::Render(other)
+    }
+}
+
+#[derive(Debug)]
+pub enum Tag {
+    Open(String, Args),
+    Close(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    positional: Vec<String>,
+    named: HashMap<String, String>,
+}
+
+impl Args {
+    pub fn with_positional(positional: Vec<String>) -> Args {
+        Args {
+            positional,
+            named: HashMap::new(),
+        }
+    }
+
+    /// Looks up an argument by name, falling back to its position
+    pub fn get(&self, name: &str, position: usize) -> Option<&str> {
+        self.named
+            .get(name)
+            .or_else(|| self.positional.get(position))
+            .map(String::as_str)
+    }
+
+    pub fn named(&self, name: &str) -> Option<&str> {
+        self.named.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &'static str, position: usize) -> Result<&str, ShortcodeError> {
+        self.get(name, position)
+            .filter(|v| !v.is_empty())
+            .ok_or(ShortcodeError::MissingArg(name))
+    }
+}
+
+/// Parses `[[name arg key=value key="quoted value"]]` or `[[/name]]`,
+/// returning `None` if the text is not shaped like a shortcode at all
+pub fn parse(text: &str) -> Option<Result<Tag, ShortcodeError>> {
+    let inner = text.trim().strip_prefix("[[")?.strip_suffix("]]")?.trim();
+    if inner.contains("[[") || inner.contains("]]") {
+        return None;
+    }
+
+    if let Some(name) = inner.strip_prefix('/') {
+        let name = name.trim();
+        return if is_name(name) {
+            Some(Ok(Tag::Close(name.to_string())))
+        } else {
+            Some(Err(ShortcodeError::Syntax(format!(
+                "invalid shortcode name '{}'",
+                name
+            ))))
+        };
+    }
+
+    let mut tokens = match tokenize(inner) {
+        Ok(tokens) => tokens.into_iter(),
+        Err(e) => return Some(Err(e)),
+    };
+
+    let name = match tokens.next() {
+        Some((None, name)) if is_name(&name) => name,
+        Some((_, name)) => {
+            return Some(Err(ShortcodeError::Syntax(format!(
+                "invalid shortcode name '{}'",
+                name
+            ))))
+        }
+        None => return Some(Err(ShortcodeError::Syntax("empty shortcode".into()))),
+    };
+
+    let mut args = Args::default();
+    for (key, value) in tokens {
+        match key {
+            Some(key) => {
+                args.named.insert(key, value);
+            }
+            None => args.positional.push(value),
+        }
+    }
+
+    Some(Ok(Tag::Open(name, args)))
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Option<String>, String)>, ShortcodeError> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = None;
+        let mut value = read_value(&mut chars)?;
+
+        if chars.next_if_eq(&'=').is_some() {
+            if value.is_empty() || !is_name(&value) {
+                return Err(ShortcodeError::Syntax(format!(
+                    "invalid argument name '{}'",
+                    value
+                )));
+            }
+            key = Some(value);
+            value = read_value(&mut chars)?;
+        }
+
+        match chars.peek() {
+            Some(c) if !c.is_whitespace() => {
+                return Err(ShortcodeError::Syntax(format!(
+                    "unexpected '{}' after '{}'",
+                    c, value
+                )))
+            }
+            _ => tokens.push((key, value)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn read_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, ShortcodeError> {
+    let mut value = String::new();
+
+    if chars.next_if_eq(&'"').is_some() {
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                Some('"') => return Ok(value),
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(ShortcodeError::Syntax(
+            "unterminated quoted argument".into(),
+        ))
+    } else {
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=' && *c != '"') {
+            value.push(c);
+        }
+        Ok(value)
+    }
+}
+
+fn valid_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 64
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Gist file names end up in a url fragment, so only plain names are allowed
+fn valid_file(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 128
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Media sources must be served by us, the content security policy blocks
+/// images and audio from other origins
+fn valid_src(value: &str) -> bool {
+    value.starts_with('/') && !value.starts_with("//") && !value.starts_with("/\\")
+}
+
+fn optional_number(args: &Args, name: &'static str) -> Result<Option<u32>, ShortcodeError> {
+    args.named(name)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| ShortcodeError::InvalidArg(name, v.to_string()))
+        })
+        .transpose()
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/youtube.html")]
+struct Youtube<'a> {
+    id: &'a str,
+    start: Option<u32>,
+    title: &'a str,
+}
+
+fn render_youtube(args: &Args, _body: Option<&str>) -> Result<String, ShortcodeError> {
+    let id = args.required("id", 0)?;
+    if !valid_id(id) {
+        return Err(ShortcodeError::InvalidArg("id", id.to_string()));
+    }
+
+    Ok(Youtube {
+        id,
+        start: optional_number(args, "start")?,
+        title: args.named("title").unwrap_or("YouTube embedded video"),
+    }
+    .render()?)
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/vimeo.html")]
+struct Vimeo<'a> {
+    id: &'a str,
+    title: &'a str,
+}
+
+fn render_vimeo(args: &Args, _body: Option<&str>) -> Result<String, ShortcodeError> {
+    let id = args.required("id", 0)?;
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ShortcodeError::InvalidArg("id", id.to_string()));
+    }
+
+    Ok(Vimeo {
+        id,
+        title: args.named("title").unwrap_or("Vimeo embedded video"),
+    }
+    .render()?)
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/gist.html")]
+struct Gist<'a> {
+    user: &'a str,
+    id: &'a str,
+    file: Option<&'a str>,
+}
+
+fn render_gist(args: &Args, _body: Option<&str>) -> Result<String, ShortcodeError> {
+    let (user, id) = match (args.named("user"), args.named("id")) {
+        (Some(user), Some(id)) => (user, id),
+        _ => {
+            let path = args.required("id", 0)?;
+            path.split_once('/')
+                .ok_or_else(|| ShortcodeError::InvalidArg("id", path.to_string()))?
+        }
+    };
+
+    if !valid_id(user) {
+        return Err(ShortcodeError::InvalidArg("user", user.to_string()));
+    }
+    if !valid_id(id) {
+        return Err(ShortcodeError::InvalidArg("id", id.to_string()));
+    }
+
+    let file = args.named("file");
+    if let Some(file) = file.filter(|file| !valid_file(file)) {
+        return Err(ShortcodeError::InvalidArg("file", file.to_string()));
+    }
+
+    Ok(Gist { user, id, file }.render()?)
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/figure.html")]
+struct Figure<'a> {
+    src: &'a str,
+    alt: &'a str,
+    caption: Option<&'a str>,
+}
+
+fn render_figure(args: &Args, _body: Option<&str>) -> Result<String, ShortcodeError> {
+    let src = args.required("src", 0)?;
+    if !valid_src(src) {
+        return Err(ShortcodeError::InvalidArg("src", src.to_string()));
+    }
+
+    let caption = args.get("caption", 1);
+
+    Ok(Figure {
+        src,
+        alt: args.named("alt").or(caption).unwrap_or(""),
+        caption,
+    }
+    .render()?)
+}
+
+const CALLOUT_KINDS: &[&str] = &["note", "tip", "info", "warning", "danger"];
+
+/// Every class the shortcode templates put on an element, the only ones
+/// kept in sanitized posts besides the renderer's own
+#[rustfmt::skip]
+pub const CLASSES: &[(&str, &[&str])] = &[
+    ("a", &["gist-link", "youtube-link"]),
+    ("aside", &["callout", "callout-note", "callout-tip", "callout-info", "callout-warning", "callout-danger"]),
+    ("div", &["shortcode-error", "gist-container", "vimeo-container", "youtube-container", "youtube-play-button"]),
+    ("figure", &["audio"]),
+    ("p", &["callout-title"]),
+    ("span", &["gist-owner", "gist-id"]),
+];
+
+#[derive(Template)]
+#[template(path = "shortcodes/callout.html")]
+struct Callout<'a> {
+    kind: &'a str,
+    title: Option<&'a str>,
+    body: &'a str,
+}
+
+fn render_callout(args: &Args, body: Option<&str>) -> Result<String, ShortcodeError> {
+    let kind = args.get("kind", 0).unwrap_or("note");
+    if !CALLOUT_KINDS.contains(&kind) {
+        return Err(ShortcodeError::InvalidArg("kind", kind.to_string()));
+    }
+
+    Ok(Callout {
+        kind,
+        title: args.get("title", 1),
+        body: body.unwrap_or(""),
+    }
+    .render()?)
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/audio.html")]
+struct Audio<'a> {
+    src: &'a str,
+    caption: Option<&'a str>,
+}
+
+fn render_audio(args: &Args, _body: Option<&str>) -> Result<String, ShortcodeError> {
+    let src = args.required("src", 0)?;
+    if !valid_src(src) {
+        return Err(ShortcodeError::InvalidArg("src", src.to_string()));
+    }
+
+    Ok(Audio {
+        src,
+        caption: args.get("caption", 1),
+    }
+    .render()?)
+}
+
+#[derive(Template)]
+#[template(path = "shortcodes/error.html")]
+struct ShortcodeErrorView<'a> {
+    source: &'a str,
+    error: &'a ShortcodeError,
+}
+
+pub fn render_error(source: &str, error: &ShortcodeError) -> String {
+    ShortcodeErrorView { source, error }
+        .render()
+        .unwrap_or_else(|_| String::from(r#"<div class="shortcode-error"></div>"#))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> String {
+        match parse(text) {
+            Some(Err(e)) => e.to_string(),
+            other => panic!("expected an error for {}, got {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let Some(Ok(Tag::Open(name, args))) =
+            parse(r#" [[figure /img/a.png alt="A \"quoted\" [x]" loading=lazy ""]] "#)
+        else {
+            panic!("expected an opening shortcode");
+        };
+        assert_eq!(name, "figure");
+        assert_eq!(args.get("src", 0), Some("/img/a.png"));
+        assert_eq!(args.named("alt"), Some(r#"A "quoted" [x]"#));
+        assert_eq!(args.named("loading"), Some("lazy"));
+        assert_eq!(args.get("caption", 1), Some(""));
+
+        assert!(matches!(parse("[[/callout]]"), Some(Ok(Tag::Close(name))) if name == "callout"));
+    }
+
+    #[test]
+    fn ignores_text_that_is_not_a_shortcode() {
+        assert!(parse("plain text").is_none());
+        assert!(parse("[[toc]] and more").is_none());
+        assert!(parse("[[a]] [[b]]").is_none());
+        assert!(parse("[link](/url)").is_none());
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert_eq!(parse_err("[[]]"), "empty shortcode");
+        assert_eq!(
+            parse_err("[[bad!name]]"),
+            "invalid shortcode name 'bad!name'"
+        );
+        assert_eq!(parse_err("[[key=value]]"), "invalid shortcode name 'value'");
+        assert_eq!(
+            parse_err("[[/bad name]]"),
+            "invalid shortcode name 'bad name'"
+        );
+        assert_eq!(
+            parse_err(r#"[[figure alt="open]]"#),
+            "unterminated quoted argument"
+        );
+        assert_eq!(parse_err("[[figure =value]]"), "invalid argument name ''");
+        assert_eq!(
+            parse_err("[[figure bad!=value]]"),
+            "invalid argument name 'bad!'"
+        );
+        assert_eq!(parse_err(r#"[[figure "a"b]]"#), "unexpected 'b' after 'a'");
+    }
+
+    #[test]
+    fn validates_arguments() {
+        let render = |name: &str, positional: &[&str]| {
+            let args = Args::with_positional(positional.iter().map(|s| s.to_string()).collect());
+            lookup(name).unwrap().render(&args, None)
+        };
+
+        assert!(render("figure", &["/img/a.png"]).is_ok());
+        for src in [
+            "https://example.com/a.png",
+            "//example.com/a.png",
+            "/\\example.com",
+            "a.png",
+        ] {
+            assert!(
+                matches!(
+                    render("figure", &[src]),
+                    Err(ShortcodeError::InvalidArg("src", _))
+                ),
+                "{}",
+                src
+            );
+        }
+        assert!(matches!(
+            render("audio", &[]),
+            Err(ShortcodeError::MissingArg("src"))
+        ));
+        assert!(matches!(
+            render("youtube", &["bad id"]),
+            Err(ShortcodeError::InvalidArg("id", _))
+        ));
+        assert!(matches!(
+            render("callout", &["shout"]),
+            Err(ShortcodeError::InvalidArg("kind", _))
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer};
+use opentelemetry_sdk::Resource;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use super::Config;
+
+use std::collections::HashMap;
+
+/// Builds a layer exporting spans over OTLP/HTTP when an endpoint is
+/// configured, must be called from within the tokio runtime
+pub fn layer<S>(config: &Config) -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = config.otlp_endpoint.as_ref()?;
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint.to_string().trim_end_matches('/'));
+    let trace_config = opentelemetry_sdk::trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.otlp_sample_ratio,
+        ))))
+        .with_resource(Resource::new([
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]));
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config)
+        .install_batch(opentelemetry_sdk::runtime::Tokio);
+
+    match tracer {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(err) => {
+            eprintln!("Unable to start otlp exporter: {}", err);
+            None
+        }
+    }
+}
+
+/// Flushes any spans still waiting in the batch exporter
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Continues the trace described by an incoming `traceparent` header
+pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    span.set_parent(parent);
+}
+
+/// Attaches the `traceparent` header to an outgoing request so it joins the
+/// current span's trace
+pub fn propagate(mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let context = tracing::Span::current().context();
+    let mut headers = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut headers)
+    });
+
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use futures::future::BoxFuture;
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use std::sync::{Arc, Mutex};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// Keeps exported spans in memory in place of an OTLP collector
+    #[derive(Debug, Clone, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn propagates_traceparent() {
+        let exporter = MemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mut headers = HeaderMap::new();
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        headers.insert("traceparent", HeaderValue::from_str(&traceparent).unwrap());
+
+        let token_request = tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request");
+            set_parent(&request, &headers);
+            let _request = request.enter();
+
+            let token = tracing::info_span!("oauth::token");
+            let _token = token.enter();
+            propagate(reqwest::Client::new().post("http://127.0.0.1/token"))
+                .build()
+                .unwrap()
+        });
+        provider.force_flush();
+
+        let spans = exporter.0.lock().unwrap();
+        let span = |name| spans.iter().find(|span| span.name == name).unwrap();
+        let (request, token) = (span("request"), span("oauth::token"));
+
+        let trace_id = TraceId::from_hex(TRACE_ID).unwrap();
+        assert_eq!(request.span_context.trace_id(), trace_id);
+        assert_eq!(request.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+        assert_eq!(token.span_context.trace_id(), trace_id);
+        assert_eq!(token.parent_span_id, request.span_context.span_id());
+
+        assert_eq!(
+            token_request.headers()["traceparent"],
+            format!("00-{}-{}-01", trace_id, token.span_context.span_id()).as_str()
+        );
+    }
+}
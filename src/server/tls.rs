@@ -0,0 +1,136 @@
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio_rustls::TlsAcceptor;
+
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Serves the most recently loaded certificate, new handshakes pick up a
+/// reloaded certificate while established connections keep their session
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> io::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let current = RwLock::new(Arc::new(load_certified_key(&cert_path, &key_path)?));
+
+        Ok(CertResolver {
+            cert_path,
+            key_path,
+            current,
+        })
+    }
+
+    #[tracing::instrument(name = "tls::reload", skip_all)]
+    fn reload(&self) {
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+                tracing::info!("reloaded tls certificate: {}", self.cert_path.display());
+            }
+            Err(err) => {
+                tracing::error!(
+                    "unable to reload tls certificate, keeping previous: {}",
+                    err
+                )
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Reloads the certificate when either file changes on disk or the
+    /// process receives SIGHUP
+    pub async fn watch(self: Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("unable to listen to sighup");
+        let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+        let mut last_modified = self.modified();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    last_modified = self.modified();
+                    self.reload();
+                }
+                _ = interval.tick() => {
+                    let modified = self.modified();
+                    if modified.is_some() && modified != last_modified {
+                        last_modified = modified;
+                        self.reload();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+pub fn acceptor(resolver: Arc<CertResolver>) -> TlsAcceptor {
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    TlsAcceptor::from(Arc::new(config))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut certs = BufReader::new(std::fs::File::open(cert_path)?);
+    let certs: Vec<_> = rustls_pemfile::certs(&mut certs)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "no certificates found in {}",
+            cert_path.display()
+        )));
+    }
+
+    let mut keys = BufReader::new(std::fs::File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut keys)? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => {
+                return Err(invalid(format!(
+                    "no private key found in {}",
+                    key_path.display()
+                )))
+            }
+        }
+    };
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| invalid(format!("unsupported private key: {}", e)))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
@@ -4,10 +4,17 @@ use super::db::Connection;
 use super::error::Resource;
 use super::Error;
 
+pub const ADMIN_ROLE: &str = "admin";
+/// Users without a stored role, including those stored before roles
+/// existed, have their posts sanitized until given a raw html role
+pub const DEFAULT_ROLE: &str = "author";
+
 #[derive(Serialize, Deserialize)]
 pub struct User {
     pub id: u64,
     pub name: String,
+    #[serde(skip)]
+    pub role: String,
 }
 
 pub struct MaybeUser(Option<User>);
@@ -16,7 +23,7 @@ impl redis::FromRedisValue for MaybeUser {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<MaybeUser> {
         match ::std::collections::HashMap::<String, String>::from_redis_value(v) {
             Ok(mut h) => {
-                if h.len() == 0 {
+                if h.is_empty() {
                     return Ok(MaybeUser(None));
                 }
                 let if_error = |s| (redis::ErrorKind::ResponseError, s);
@@ -27,8 +34,9 @@ impl redis::FromRedisValue for MaybeUser {
                 let name = h
                     .remove("name")
                     .ok_or_else(|| if_error("Unexpected user name"))?;
+                let role = h.remove("role").unwrap_or_else(|| DEFAULT_ROLE.to_string());
 
-                Ok(MaybeUser(Some(User { id, name })))
+                Ok(MaybeUser(Some(User { id, name, role })))
             }
             Err(e) => Err(e),
         }
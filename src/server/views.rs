@@ -1,56 +1,153 @@
 use askama::Template;
 
+use super::caching::{CachePolicy, Cached, Conditional, Validators, ValidatorsBuilder};
 use super::db::Connection;
+use super::markdown::{Renderer, RENDERER_VERSION};
 use super::models::*;
-use super::posts::PostClient;
+use super::posts::{Post, PostClient, Revision};
+use super::request_id::RequestId;
 use super::users::User;
 use super::Error;
 
 const PAGE_SIZE: i64 = 10;
 
-pub async fn index(user: Option<User>, db: Connection, page: Option<i64>) -> Result<String, Error> {
-    let post_client = PostClient::new(db);
+pub async fn index(
+    user: Option<User>,
+    db: Connection,
+    renderer: &Renderer,
+    conditional: &Conditional,
+    page: Option<i64>,
+) -> Result<Cached<String>, Error> {
+    let mut post_client = PostClient::new(db);
     let page = page.unwrap_or(1);
     let current_page = if page == 0 { 1 } else { page };
+    let revision = post_client.revision().await?;
     let page = post_client
         .get_all(PAGE_SIZE, (current_page - 1) * PAGE_SIZE)
         .await?;
+
+    let validators = page
+        .posts
+        .iter()
+        .fold(
+            view_validators("index", &user, revision).add(current_page),
+            add_post,
+        )
+        .build();
+    let policy = CachePolicy::for_user(user.as_ref());
+    if conditional.not_modified(&validators) {
+        return Ok(Cached::not_modified(validators, policy));
+    }
+
+    let contents = renderer.render_all(&page.posts, user.as_ref()).await;
+    let posts = page
+        .posts
+        .into_iter()
+        .zip(contents)
+        .map(|(post, content)| RenderedPost::new(post, content))
+        .collect();
     let model = PostIndex {
-        page,
+        posts,
+        has_more: page.has_more,
         current_page,
         user,
     };
 
-    model.render().map_err(|e| Error::Render(("index", e)))
+    let html = model.render().map_err(|e| Error::Render(("index", e)))?;
+    Ok(Cached::fresh(validators, policy, html))
 }
 
-pub async fn post_id(user: Option<User>, db: Connection, post: u64) -> Result<String, Error> {
-    let post_client = PostClient::new(db);
+pub async fn post_id(
+    user: Option<User>,
+    db: Connection,
+    renderer: &Renderer,
+    conditional: &Conditional,
+    post: u64,
+) -> Result<Cached<String>, Error> {
+    let mut post_client = PostClient::new(db);
+    let revision = post_client.revision().await?;
     let post = post_client.get(post).await?;
-    let model = PostView { post, user };
-    model.render().map_err(|e| Error::Render(("post_id", e)))
+    post_view(user, renderer, conditional, revision, post)
+        .await
+        .map_err(|e| Error::Render(("post_id", e)))
 }
 
 pub async fn post_frag(
     user: Option<User>,
     db: Connection,
+    renderer: &Renderer,
+    conditional: &Conditional,
     frag: impl AsRef<str>,
-) -> Result<String, Error> {
-    let post_client = PostClient::new(db);
+) -> Result<Cached<String>, Error> {
+    let mut post_client = PostClient::new(db);
     let frag = frag.as_ref().to_string();
+    let revision = post_client.revision().await?;
     let post = post_client.get_by_fragment(frag).await?;
+    post_view(user, renderer, conditional, revision, post)
+        .await
+        .map_err(|e| Error::Render(("post_frag", e)))
+}
+
+async fn post_view(
+    user: Option<User>,
+    renderer: &Renderer,
+    conditional: &Conditional,
+    revision: Revision,
+    post: Post,
+) -> Result<Cached<String>, askama::Error> {
+    let validators = add_post(view_validators("post", &user, revision), &post).build();
+    let policy = CachePolicy::for_user(user.as_ref());
+    if conditional.not_modified(&validators) {
+        return Ok(Cached::not_modified(validators, policy));
+    }
+
+    let content = renderer.render_one(&post, user.as_ref()).await;
+    let post = RenderedPost::new(post, content);
     let model = PostView { post, user };
-    model.render().map_err(|e| Error::Render(("post_frag", e)))
+    Ok(Cached::fresh(validators, policy, model.render()?))
 }
 
-pub fn not_found(user: Option<User>) -> Result<String, Error> {
-    NotFound { user }
-        .render()
-        .map_err(|e| Error::Render(("not_found", e)))
+fn view_validators(kind: &str, user: &Option<User>, revision: Revision) -> ValidatorsBuilder {
+    let builder = Validators::builder(kind)
+        .add(RENDERER_VERSION)
+        .add(revision.counter)
+        .modified(revision.modified);
+
+    match user {
+        Some(user) => builder.add(user.id).add(&user.name),
+        None => builder.add("anonymous"),
+    }
 }
 
-pub fn error(user: Option<User>, _error: &Error) -> Result<String, Error> {
-    NotFound { user }
+pub fn add_post(builder: ValidatorsBuilder, post: &Post) -> ValidatorsBuilder {
+    builder
+        .add(post.id)
+        .add(post.date)
+        .add(post.author.as_deref().unwrap_or(""))
+        .modified(post.date)
+}
+
+pub fn not_found(user: Option<User>, request_id: Option<RequestId>) -> Result<String, Error> {
+    NotFound { user, request_id }
         .render()
         .map_err(|e| Error::Render(("not_found", e)))
 }
+
+pub fn error(
+    user: Option<User>,
+    error: &Error,
+    request_id: Option<RequestId>,
+) -> Result<String, Error> {
+    let heading = match error {
+        Error::RateLimited(_) => "Too Many Requests",
+        _ => "Something Went Wrong",
+    };
+
+    ServerError {
+        user,
+        heading,
+        request_id,
+    }
+    .render()
+    .map_err(|e| Error::Render(("error", e)))
+}